@@ -0,0 +1,179 @@
+// Copyright 2019, 2020 Rohde & Schwarz GmbH & Co KG
+//      philipp.stanner@rohde-schwarz.com
+//      hagen.pfeifer@rohde-schwarz.com
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Transport-agnostic wire protocol: framing and (de)serialization only,
+// no sockets. This is what a `no_std` + `alloc` embedded build links
+// against instead of `tcp_handler`, which additionally depends on
+// `std::net`/`mio`. Everything here used to live in `tcp_handler`; it was
+// pulled out so the framing and tracepoint-enable/disable parsing can be
+// reused unchanged by any `Transport` impl (see `transport.rs`).
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+pub const HEADER_LEN: usize = 12;
+
+// magic nr: 'RuSt'
+pub const MAGIC_NUMB: [u8; 4] = [0x52, 0x75, 0x53, 0x74];
+
+#[repr(u16)]
+#[derive(Clone, Copy)]
+pub enum Command {
+    TracepointListRequest       = 1,
+    TracepointListReply         = 2,
+    TracepointEnableRequest     = 3,
+    TracepointDisableRequest    = 4,
+    TracePush                   = 5,
+    Invalid                     = 42,
+}
+
+pub fn push_front_header(que: &mut VecDeque<u8>, cmd: Command)
+{
+    // flags are currently unused
+    let flags: u16 = 0;
+    let length = que.len() as u32;
+    for byte in length.to_be_bytes().iter().rev() {
+        que.push_front(*byte);
+    }
+
+    let tmp = cmd as u16;
+    for byte in tmp.to_be_bytes().iter().rev() {
+        que.push_front(*byte);
+    }
+
+    for byte in flags.to_be_bytes().iter().rev() {
+        que.push_front(*byte);
+    }
+
+    for byte in MAGIC_NUMB.iter().rev() {
+        que.push_front(*byte);
+    }
+}
+
+// Encodes one already-timestamped tracepoint submission. The caller
+// supplies the timestamp as a raw u64 (e.g. nanoseconds since whatever
+// epoch the platform's `Clock` impl uses) instead of a `SystemTime`,
+// since that type doesn't exist without `std`. `ctx_id` is the opaque
+// correlation id `tracy_submit_ctx` lets a caller attach to a
+// submission (0 for the plain `tracy_submit` path); it's serialized
+// unconditionally rather than behind a flag so a receiver never has to
+// special-case whether a given frame carries one.
+pub fn encode_append_trace_data(que: &mut VecDeque<u8>, tracepoint: &str,
+                                timestamp: u64, ctx_id: u64, data: &[u8])
+{
+    let tp_len = tracepoint.len() as u16;
+    for byte in tp_len.to_be_bytes().iter() {
+        que.push_back(*byte);
+    }
+
+    for byte in tracepoint.as_bytes() {
+        que.push_back(*byte);
+    }
+
+    for byte in timestamp.to_be_bytes().iter() {
+        que.push_back(*byte);
+    }
+
+    for byte in ctx_id.to_be_bytes().iter() {
+        que.push_back(*byte);
+    }
+
+    let data_len = data.len() as u16;
+    for byte in data_len.to_be_bytes().iter() {
+        que.push_back(*byte);
+    }
+
+    for byte in data {
+        que.push_back(*byte);
+    }
+}
+
+pub fn check_parse_header(header: &[u8; 12]) -> Result<(Command, u32), ()>
+{
+    let mut magic_no: [u8; 4] = [0; 4];
+    let mut flags: [u8; 2] = [0; 2];
+    let mut command: [u8; 2] = [0; 2];
+    let mut length: [u8; 4] = [0; 4];
+
+    magic_no.copy_from_slice(&header[0..4]);
+    if !check_magic_number(magic_no) {
+        return Err(());
+    }
+
+    flags.copy_from_slice(&header[4..6]);
+    command.copy_from_slice(&header[6..8]);
+    length.copy_from_slice(&header[8..12]);
+
+    let len = u32::from_be_bytes(length);
+    let flags = u16::from_be_bytes(flags);
+    let cmd = u16::from_be_bytes(command);
+
+    let cmd = cmd_number_to_enum(cmd);
+    check_cmd_validity(&cmd, len)?;
+    check_flags(flags)?;
+
+    Ok((cmd, len))
+}
+
+// Flags are currently unused. If they're not all 0, reject request
+fn check_flags(flags: u16) -> Result<(), ()>
+{
+    if flags != 0 {
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
+fn cmd_number_to_enum(cmd: u16) -> Command
+{
+    match cmd {
+        cmd if cmd == Command::TracepointListRequest as u16 =>
+            Command::TracepointListRequest,
+        cmd if cmd == Command::TracepointEnableRequest as u16 =>
+            Command::TracepointEnableRequest,
+        cmd if cmd == Command::TracepointDisableRequest as u16 =>
+            Command::TracepointDisableRequest,
+        cmd if cmd == Command::TracepointListReply as u16 =>
+            Command::TracepointListReply,
+        cmd if cmd == Command::TracePush as u16 =>
+            Command::TracePush,
+        _ =>
+            Command::Invalid,
+    }
+}
+
+fn check_cmd_validity(cmd: &Command, len: u32) -> Result<(), ()>
+{
+    match cmd {
+        Command::TracepointListRequest =>
+            if len != 0 { Err(()) } else { Ok(()) },
+        Command::TracepointEnableRequest =>
+            if len == 0 { Err(()) } else { Ok(()) },
+        Command::TracepointDisableRequest =>
+            if len == 0 { Err(()) } else { Ok(()) },
+        // Client is only allowed to give the upper commands
+        _ => Err(()),
+    }
+}
+
+fn check_magic_number(number: [u8; 4]) -> bool
+{
+    number == MAGIC_NUMB
+}
+
+// Flattens a VecDeque's two backing slices into one contiguous buffer,
+// for transports (sockets, rings, UART DMA, ...) that want a single `&[u8]`.
+pub fn flatten(que: &VecDeque<u8>) -> Vec<u8>
+{
+    let (first, second) = que.as_slices();
+    let mut out = Vec::with_capacity(que.len());
+    out.extend_from_slice(first);
+    out.extend_from_slice(second);
+    out
+}
@@ -0,0 +1,233 @@
+// Copyright 2019, 2020 Rohde & Schwarz GmbH & Co KG
+//      philipp.stanner@rohde-schwarz.com
+//      hagen.pfeifer@rohde-schwarz.com
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// QUIC is an optional peer of `tcp_handler`: same `Command` wire format
+// (see tcp_handler::{push_front_header, check_parse_header}), but carried
+// inside QUIC streams/datagrams instead of a single plaintext TCP
+// connection, so a stalled or lost frame for one tracepoint no longer
+// stalls the others.
+//
+// FIXME: Only unidirectional streams are mapped today. Small batches that
+// would fit a single UDP datagram should go out as a QUIC DATAGRAM frame
+// instead of opening a stream, once neqo's datagram support is wired up.
+
+use mio::*;
+use mio::net::UdpSocket;
+
+use neqo_crypto::AntiReplay;
+use neqo_transport::{Connection, ConnectionIdManager, Output, Role, State};
+
+use std::net::SocketAddr;
+use std::io::ErrorKind;
+use std::collections::HashMap;
+
+use crate::std_runtime::{TracerContext, QUEUE_TOTAL_SIZE};
+use crate::std_runtime::tcp_handler;
+use crate::protocol::Command;
+
+pub(crate) const CON_QUIC: Token = Token(5);
+
+const MAX_DATAGRAM_SIZE: usize = 1452;
+
+// One accepted QUIC peer. `streams` maps a tracepoint to its already-open
+// unidirectional stream, so a stalled reader on one tracepoint's stream
+// only stalls that tracepoint, not the others.
+pub(crate) struct QuicPeer {
+    pub(crate) remote: SocketAddr,
+    pub(crate) conn: Connection,
+    streams: HashMap<String, u64>,
+}
+
+// Bound but otherwise uninitialized: each peer's `Connection` is created
+// lazily in `accept_peer` on its first datagram, same as `tcp_handler::init`
+// defers connection setup to `establish_connection`.
+pub(crate) struct QuicTransport {
+    pub(crate) sock: UdpSocket,
+    pub(crate) peers: Vec<QuicPeer>,
+}
+
+// FIXME: `cert_path`/`key_path` come from `InitData` (the TLS config
+// surface `tracy_init` exposes) but aren't loaded into an NSS
+// certificate/key pair yet -- `accept_peer` only ever has neqo's built-in
+// test certificate to hand `Connection::new_server`. Wiring a
+// caller-supplied cert through needs an NSS DB import step this first
+// QUIC pass doesn't do, so a caller who actually configured one is
+// refused here rather than silently handed a connection authenticated
+// under the wrong identity.
+pub(crate) fn init(bind_addr: SocketAddr,
+                    cert_path: Option<String>,
+                    key_path: Option<String>) -> Option<QuicTransport>
+{
+    if cert_path.is_some() || key_path.is_some() {
+        eprintln!("tracy: QUIC: Custom cert/key configured but not yet supported; \
+                   refusing to start QUIC under the wrong identity.");
+        return None;
+    }
+
+    match UdpSocket::bind(&bind_addr) {
+        Ok(sock) => {
+            println!("tracy: QUIC: Bound to {}.", bind_addr);
+            Some(QuicTransport { sock, peers: Vec::new() })
+        },
+        Err(e) => {
+            eprintln!("tracy: QUIC: Could not bind UDP socket: {}", e);
+            None
+        },
+    }
+}
+
+// Accepts a new QUIC peer under neqo's built-in test certificate (no PSK
+// mode exists yet -- see the FIXME on `init`). Mirrors
+// `tcp_handler::establish_connection`: a failure here is logged and the
+// rest of the tracer keeps running.
+pub(crate) fn accept_peer(ctx: &mut TracerContext, remote: SocketAddr)
+{
+    let cid_mgr = ConnectionIdManager::default();
+    let anti_replay = AntiReplay::new_none();
+
+    let conn = match Connection::new_server(
+        &["libtracy"], &["libtracy-quic"], cid_mgr, Role::Server, &anti_replay)
+    {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("tracy: QUIC: Could not create server connection: {:?}", e);
+            return;
+        },
+    };
+
+    if let Some(quic) = ctx.quic.as_mut() {
+        quic.peers.push(QuicPeer { remote, conn, streams: HashMap::new() });
+    }
+}
+
+// Drops peers whose connection has closed or is closing, so a dead
+// `QuicPeer` doesn't linger forever or get matched by `receive`'s
+// address lookup instead of getting a fresh `Connection` via `accept_peer`.
+fn prune_closed_peers(quic: &mut QuicTransport)
+{
+    quic.peers.retain(|p| !matches!(p.conn.state(),
+        State::Closing { .. } | State::Draining { .. } | State::Closed(..)));
+}
+
+// Pumps `peer`'s output queue onto the wire. `neqo_transport::Connection`
+// is a pure state machine -- `process_input`/`stream_send` only update its
+// internal state, neither ever touches the socket -- so nothing (handshake
+// flight, ACK, or a just-queued TracePush stream) reaches the peer unless
+// this runs afterwards.
+fn flush_peer(sock: &UdpSocket, peer: &mut QuicPeer)
+{
+    loop {
+        match peer.conn.process_output(std::time::Instant::now()) {
+            Output::Datagram(dgram) => {
+                if let Err(e) = sock.send_to(&dgram, dgram.destination()) {
+                    eprintln!("tracy: QUIC: send failed: {}", e);
+                }
+            },
+            Output::Callback(_) | Output::None => break,
+        }
+    }
+}
+
+// Reads and processes pending datagrams on the QUIC socket, feeding them
+// into the matching peer's connection state machine and flushing whatever
+// that produces (handshake response, ACK, ...) straight back out.
+pub(crate) fn receive(ctx: &mut TracerContext)
+{
+    if let Some(quic) = ctx.quic.as_mut() {
+        prune_closed_peers(quic);
+    }
+
+    let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, from) = match ctx.quic.as_ref().unwrap().sock.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) => {
+                eprintln!("tracy: QUIC: recv failed: {}", e);
+                return;
+            },
+        };
+
+        if !ctx.quic.as_ref().unwrap().peers.iter().any(|p| p.remote == from) {
+            accept_peer(ctx, from);
+        }
+
+        if let Some(quic) = ctx.quic.as_mut() {
+            let QuicTransport { sock, peers } = quic;
+            if let Some(peer) = peers.iter_mut().find(|p| p.remote == from) {
+                peer.conn.process_input(&buf[..len], std::time::Instant::now());
+                flush_peer(sock, peer);
+            }
+        }
+    }
+}
+
+// Groups the drained buffer by tracepoint, then serializes each group the
+// same way `tcp_handler::send_trace_data` does (reusing the `Command`
+// framing) and writes it to that tracepoint's own unidirectional stream
+// (opened once per peer, reused across flushes -- see `QuicPeer::streams`).
+// Keeping every tracepoint on its own stream is the whole point of this
+// transport: a peer that is slow to read one tracepoint's stream only
+// stalls that tracepoint, not the rest, unlike the one shared TCP
+// connection `tcp_handler::send_trace_data` fans everything through.
+pub(crate) fn send_trace_data(ctx: &mut TracerContext)
+{
+    if let Some(quic) = ctx.quic.as_mut() {
+        prune_closed_peers(quic);
+    }
+
+    if ctx.quic.is_none() || ctx.buffer.is_empty() {
+        return;
+    }
+
+    let mut by_tracepoint: HashMap<String, std::collections::VecDeque<u8>> = HashMap::new();
+
+    while let Some(elem) = ctx.buffer.pop_front() {
+        let que = by_tracepoint.entry(elem.tracepoint.clone())
+            .or_insert_with(|| std::collections::VecDeque::with_capacity(QUEUE_TOTAL_SIZE));
+        tcp_handler::encode_append_trace_data(que, elem);
+    }
+
+    let quic = ctx.quic.as_mut().unwrap();
+    let QuicTransport { sock, peers } = quic;
+
+    for (tracepoint, mut que) in by_tracepoint {
+        tcp_handler::push_front_header(&mut que, Command::TracePush);
+        let (first, second) = que.as_slices();
+        let mut frame = Vec::with_capacity(que.len());
+        frame.extend_from_slice(first);
+        frame.extend_from_slice(second);
+
+        for peer in peers.iter_mut() {
+            if *peer.conn.state() != State::Confirmed {
+                continue;
+            }
+
+            let stream_id = match peer.streams.get(&tracepoint) {
+                Some(id) => *id,
+                None => match peer.conn.stream_create(neqo_transport::StreamType::UniDi) {
+                    Ok(id) => {
+                        peer.streams.insert(tracepoint.clone(), id);
+                        id
+                    },
+                    Err(_) => continue,
+                },
+            };
+
+            let _ = peer.conn.stream_send(stream_id, &frame);
+        }
+    }
+
+    // Streams queued above (and any handshake/ACK traffic still pending on
+    // a peer that hasn't reached Confirmed yet) only get serialized into
+    // datagrams once process_output actually runs.
+    for peer in peers.iter_mut() {
+        flush_peer(sock, peer);
+    }
+}
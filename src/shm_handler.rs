@@ -0,0 +1,173 @@
+// Copyright 2019, 2020 Rohde & Schwarz GmbH & Co KG
+//      philipp.stanner@rohde-schwarz.com
+//      hagen.pfeifer@rohde-schwarz.com
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Shared-memory transport for clients co-located on the same host. Avoids
+// the allocate-and-copy that `tcp_handler::send_slices` does on every
+// flush ("we assume that allocating & copying is less expensive than two
+// syscalls") by writing the very same `encode_append_trace_data` byte
+// layout directly into an SPSC ring mapped into both processes.
+//
+// Layout of the shared segment: a fixed-size `RingHeader` followed by
+// `RING_DATA_SIZE` bytes of data region. `write_cursor`/`read_cursor` are
+// monotonically increasing byte offsets modulo `RING_DATA_SIZE`; the
+// producer (us) only ever advances `write_cursor`, the consumer only ever
+// advances `read_cursor`.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::std_runtime::TracerContext;
+use crate::std_runtime::tcp_handler::{encode_append_trace_data, push_front_header};
+use crate::protocol::Command;
+
+pub(crate) const RING_DATA_SIZE: usize = 1 << 20; // 1 MiB
+
+#[repr(C)]
+struct RingHeader {
+    write_cursor: AtomicUsize,
+    read_cursor: AtomicUsize,
+}
+
+// Owns the memfd-backed mapping. Dropped (and thus unmapped/closed) when
+// the tracer thread tears down, same lifetime as `TracerContext::connections`.
+pub(crate) struct ShmRing {
+    fd: RawFd,
+    map: *mut u8,
+    map_len: usize,
+}
+
+impl ShmRing {
+    fn header(&self) -> &RingHeader
+    {
+        unsafe { &*(self.map as *const RingHeader) }
+    }
+
+    fn data(&self) -> *mut u8
+    {
+        unsafe { self.map.add(std::mem::size_of::<RingHeader>()) }
+    }
+
+    // Writes `bytes` into the ring, respecting the existing bounded-queue
+    // drop policy if there isn't enough free space: unlike `send_slices`
+    // (TCP), which just retries, a full shared-memory ring has no backing
+    // socket to apply backpressure through, so we drop the batch.
+    fn try_write(&self, bytes: &[u8]) -> Result<(), ()>
+    {
+        let header = self.header();
+        let write = header.write_cursor.load(Ordering::Relaxed);
+        let read = header.read_cursor.load(Ordering::Acquire);
+        let used = write.wrapping_sub(read);
+        let free = RING_DATA_SIZE - used;
+
+        if bytes.len() > free {
+            return Err(());
+        }
+
+        let data = self.data();
+        let start = write % RING_DATA_SIZE;
+        let first_chunk = std::cmp::min(bytes.len(), RING_DATA_SIZE - start);
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), data.add(start), first_chunk);
+            if first_chunk < bytes.len() {
+                std::ptr::copy_nonoverlapping(
+                    bytes[first_chunk..].as_ptr(), data, bytes.len() - first_chunk);
+            }
+        }
+
+        header.write_cursor.store(write.wrapping_add(bytes.len()), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Drop for ShmRing {
+    fn drop(&mut self)
+    {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.map_len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+// Creates the memfd-backed segment. The name shows up in
+// `/proc/<pid>/fd` for debugging, same spirit as naming the TCP listener
+// port in `tcp_handler::init`'s log line.
+pub(crate) fn init() -> io::Result<ShmRing>
+{
+    let name = std::ffi::CString::new("libtracy-ring").unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let map_len = std::mem::size_of::<RingHeader>() + RING_DATA_SIZE;
+    if unsafe { libc::ftruncate(fd, map_len as libc::off_t) } < 0 {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+
+    let map = unsafe {
+        libc::mmap(std::ptr::null_mut(), map_len,
+            libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0)
+    };
+    if map == libc::MAP_FAILED {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+
+    unsafe {
+        let header = &*(map as *const RingHeader);
+        header.write_cursor.store(0, Ordering::Relaxed);
+        header.read_cursor.store(0, Ordering::Relaxed);
+    }
+
+    println!("tracy: SHM: Created ring buffer, fd {}.", fd);
+    Ok(ShmRing { fd, map: map as *mut u8, map_len })
+}
+
+// TODO: nothing calls this yet -- the client is meant to negotiate the
+// segment by receiving this fd (e.g. over the existing TCP connection's
+// SCM_RIGHTS ancillary data during announce), but that hand-off isn't
+// wired up, so FLAG_SHM defaults off until it is.
+impl AsRawFd for ShmRing {
+    fn as_raw_fd(&self) -> RawFd
+    {
+        self.fd
+    }
+}
+
+// Writes one TracePush batch directly into the ring using the same wire
+// layout `tcp_handler::send_trace_data` produces, instead of going through
+// `send_slices`'s Vec-allocating copy.
+pub(crate) fn send_trace_data(ctx: &mut TracerContext)
+{
+    let ring = match ctx.shm.as_ref() {
+        Some(ring) => ring,
+        None => return,
+    };
+
+    let mut que: std::collections::VecDeque<u8> = std::collections::VecDeque::new();
+    while let Some(elem) = ctx.buffer.pop_front() {
+        encode_append_trace_data(&mut que, elem);
+    }
+
+    if que.is_empty() {
+        return;
+    }
+
+    push_front_header(&mut que, Command::TracePush);
+    let frame: &[u8] = que.make_contiguous();
+
+    if ring.try_write(frame).is_err() {
+        eprintln!("tracy: SHM: Ring full, dropping batch ({} bytes).", frame.len());
+    }
+}
@@ -0,0 +1,528 @@
+// Copyright 2019, 2020 Rohde & Schwarz GmbH & Co KG
+//      philipp.stanner@rohde-schwarz.com
+//      hagen.pfeifer@rohde-schwarz.com
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// `no_std` counterpart to `std_runtime`: same wire protocol and
+// tracepoint bookkeeping (both come straight from `crate::protocol`),
+// but driven by smoltcp instead of a std thread + mio's `Poll`. There is
+// no OS to spawn a thread on, so nothing here runs in the background --
+// the firmware's own main loop calls `tracy_embedded_poll` whenever it
+// wakes, and that call does one round of "pump the device, service any
+// ready sockets, flush the buffer" before returning how long until smoltcp
+// wants to be polled again.
+
+extern crate smoltcp;
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::{BTreeMap, VecDeque};
+
+use smoltcp::iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache};
+use smoltcp::socket::{SocketSet, SocketHandle, TcpSocket, TcpSocketBuffer};
+use smoltcp::wire::{EthernetAddress, IpCidr, IpAddress};
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+
+use crate::protocol::{self, Command};
+use crate::transport::{Transport, SmolSocket};
+
+// How many simultaneous TCP collectors the embedded listener pool
+// supports. Fixed at compile time: smoltcp's `SocketSet` (and the
+// buffers each `TcpSocket` wraps) are sized up front, there's no
+// allocator-backed arena to grow into the way `std_runtime::TracerContext`
+// grows its `slab::Slab` -- a full slot just gets re-armed into `listen`
+// once its connection drops instead of being removed and reinserted.
+const MAX_SESSIONS: usize = 4;
+const SOCKET_BUF_LEN: usize = 1536;
+const MAX_TRACEPOINT_NAME_LEN: usize = 32;
+
+// One pooled socket slot and the per-connection state that goes with it,
+// the embedded equivalent of `tcp_handler::ClientSession`.
+struct Session {
+    handle: SocketHandle,
+    connected: bool,
+    tracepoints: BTreeMap<String, bool>,
+    // Bytes accumulated toward the frame currently being parsed, since
+    // `TcpSocket::recv_slice` can return short; kept across
+    // `service_session` calls instead of being parsed as a whole frame.
+    rx_scratch: Vec<u8>,
+}
+
+// Raw Ethernet frame I/O, supplied by the firmware. Deliberately minimal
+// (two function pointers) rather than a full smoltcp `phy::Device` impl
+// written in C, so a caller only has to wire up whatever DMA/interrupt
+// plumbing their MAC driver already exposes.
+#[repr(C)]
+pub struct TracyDevice {
+    pub ctx: *mut core::ffi::c_void,
+    // Copies one already-arrived frame into `buf` (capacity `cap`) and
+    // returns its length, or 0 if nothing is pending.
+    pub recv: extern "C" fn(ctx: *mut core::ffi::c_void, buf: *mut u8, cap: usize) -> usize,
+    // Transmits exactly `len` bytes of `buf` as one frame; returns false
+    // on failure (e.g. the MAC's TX ring is full).
+    pub send: extern "C" fn(ctx: *mut core::ffi::c_void, buf: *const u8, len: usize) -> bool,
+    pub mtu: usize,
+}
+
+struct CRxToken(Vec<u8>);
+
+impl phy::RxToken for CRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+        where F: FnOnce(&mut [u8]) -> smoltcp::Result<R>
+    {
+        f(&mut self.0)
+    }
+}
+
+struct CTxToken<'a>(&'a TracyDevice);
+
+impl<'a> phy::TxToken for CTxToken<'a> {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+        where F: FnOnce(&mut [u8]) -> smoltcp::Result<R>
+    {
+        let mut buf = alloc::vec![0u8; len];
+        let result = f(&mut buf)?;
+
+        if !(self.0.send)(self.0.ctx, buf.as_ptr(), buf.len()) {
+            return Err(smoltcp::Error::Exhausted);
+        }
+
+        Ok(result)
+    }
+}
+
+impl<'d> Device<'d> for TracyDevice {
+    type RxToken = CRxToken;
+    type TxToken = CTxToken<'d>;
+
+    fn receive(&'d mut self) -> Option<(Self::RxToken, Self::TxToken)>
+    {
+        let mut buf = alloc::vec![0u8; self.mtu];
+        let len = (self.recv)(self.ctx, buf.as_mut_ptr(), buf.len());
+
+        if len == 0 {
+            return None;
+        }
+
+        buf.truncate(len);
+        Some((CRxToken(buf), CTxToken(self)))
+    }
+
+    fn transmit(&'d mut self) -> Option<Self::TxToken>
+    {
+        Some(CTxToken(self))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities
+    {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+// Owns everything `tracy_embedded_poll` needs: the interface/device/socket
+// storage smoltcp drives, plus the same two pieces of bookkeeping
+// `std_runtime::TracerContext` keeps -- the outbound buffer and the
+// tracepoint-name -> enabled table, both reused unchanged from
+// `crate::protocol`'s `no_std` framing.
+pub struct SmolTracer<'a> {
+    iface: EthernetInterface<'a, TracyDevice>,
+    sockets: SocketSet<'a>,
+    sessions: Vec<Session>,
+    port: u16,
+    // name, timestamp_nanos, ctx_id, data -- the no_std analogue of
+    // `std_runtime::BufferElement`, including the same `ctx_id`
+    // correlation field `tracy_submit_ctx`/`tracy_submit_ctx_embedded`
+    // let a caller attach.
+    buffer: VecDeque<(String, u64, u64, Vec<u8>)>,
+    tracepoints: BTreeMap<String, bool>,
+}
+
+// Appends however many bytes `socket` currently has buffered onto `scratch`,
+// up to `target` total, without blocking -- `TcpSocket::recv_slice` returns
+// short rather than waiting for the rest to arrive. A free function (not a
+// `SmolTracer` method) so it borrows only the `socket`/`scratch` the caller
+// already holds, not all of `self`.
+fn fill_scratch(socket: &mut TcpSocket, scratch: &mut Vec<u8>, target: usize)
+{
+    while scratch.len() < target && socket.can_recv() {
+        let mut tmp = [0u8; 64];
+        let want = core::cmp::min(tmp.len(), target - scratch.len());
+
+        match socket.recv_slice(&mut tmp[..want]) {
+            Ok(n) if n > 0 => scratch.extend_from_slice(&tmp[..n]),
+            _ => break,
+        }
+    }
+}
+
+// Accumulates a full header-plus-body frame across as many calls as it
+// takes, since a header or tracepoint name can land split across TCP
+// segments. Returns `Ok(None)` when a full frame hasn't arrived yet (the
+// caller tries again next poll), `Err(())` on a malformed header.
+fn read_frame(socket: &mut TcpSocket, scratch: &mut Vec<u8>)
+    -> Result<Option<(Command, Vec<u8>)>, ()>
+{
+    fill_scratch(socket, scratch, protocol::HEADER_LEN);
+    if scratch.len() < protocol::HEADER_LEN {
+        return Ok(None);
+    }
+
+    let mut header = [0u8; protocol::HEADER_LEN];
+    header.copy_from_slice(&scratch[..protocol::HEADER_LEN]);
+    let (cmd, len) = protocol::check_parse_header(&header).map_err(|_| ())?;
+
+    // check_parse_header only checks len against zero, not implausibly large
+    // values off the wire; cap it so a malformed frame can't grow
+    // `scratch` toward exhausting heap on a memory-constrained target.
+    if len as usize > SOCKET_BUF_LEN {
+        return Err(());
+    }
+
+    let total = protocol::HEADER_LEN + len as usize;
+    fill_scratch(socket, scratch, total);
+    if scratch.len() < total {
+        return Ok(None);
+    }
+
+    let body = scratch[protocol::HEADER_LEN..total].to_vec();
+    scratch.drain(..total);
+    Ok(Some((cmd, body)))
+}
+
+impl<'a> SmolTracer<'a> {
+    // Registers a tracepoint so `tracy_submit_embedded`/enable-disable
+    // requests recognize its name. There's no background thread to hand
+    // this off to (unlike `std_runtime::tracy_register`'s channel send --
+    // the caller's main loop *is* the tracer loop here), so it just
+    // mutates the table directly.
+    pub fn register(&mut self, name: &str)
+    {
+        self.tracepoints.entry(String::from(name)).or_insert(false);
+    }
+
+    pub fn tracepoint_enabled(&self, name: &str) -> bool
+    {
+        self.tracepoints.get(name).copied().unwrap_or(false)
+    }
+
+    pub fn submit(&mut self, name: &str, timestamp_nanos: u64, ctx_id: u64, data: &[u8])
+    {
+        if !self.tracepoint_enabled(name) {
+            return;
+        }
+
+        self.buffer.push_back(
+            (String::from(name), timestamp_nanos, ctx_id, Vec::from(data)));
+    }
+
+    // Pumps the device, services whichever pooled sockets are connected,
+    // then flushes the buffer to every connected, subscribed session.
+    // Returns how long until smoltcp wants to be polled again -- following
+    // smoltcp's own polling contract (`EthernetInterface::poll_delay`),
+    // so a bare-metal main loop can go back to sleep (on an RTC/timer
+    // interrupt, say) instead of busy-looping until then or until the
+    // device signals a new frame.
+    pub fn poll(&mut self, timestamp: Instant) -> Option<smoltcp::time::Duration>
+    {
+        match self.iface.poll(&mut self.sockets, timestamp) {
+            Ok(_) | Err(smoltcp::Error::Dropped) => (),
+            Err(e) => {
+                // Non-fatal: smoltcp already logs malformed packets
+                // internally; nothing to clean up on our side.
+                let _ = e;
+            },
+        }
+
+        for i in 0..self.sessions.len() {
+            self.service_session(i);
+        }
+
+        self.flush();
+
+        self.iface.poll_delay(&self.sockets, timestamp)
+    }
+
+    fn service_session(&mut self, idx: usize)
+    {
+        let handle = self.sessions[idx].handle;
+
+        {
+            let mut socket = self.sockets.get::<TcpSocket>(handle);
+
+            if !socket.is_open() {
+                let _ = socket.listen(self.port);
+                self.sessions[idx].connected = false;
+                self.sessions[idx].tracepoints.clear();
+                self.sessions[idx].rx_scratch.clear();
+                return;
+            }
+
+            self.sessions[idx].connected = socket.is_active();
+        }
+
+        loop {
+            let frame = {
+                let mut socket = self.sockets.get::<TcpSocket>(handle);
+                match read_frame(&mut socket, &mut self.sessions[idx].rx_scratch) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => return,
+                    Err(()) => {
+                        socket.close();
+                        self.sessions[idx].rx_scratch.clear();
+                        return;
+                    },
+                }
+            };
+
+            self.execute_command(idx, handle, frame.0, &frame.1);
+        }
+    }
+
+    fn execute_command(&mut self, idx: usize, handle: SocketHandle, cmd: Command, body: &[u8])
+    {
+        match cmd {
+            Command::TracepointListRequest => self.send_tracepoint_list(handle),
+            Command::TracepointEnableRequest => {
+                if self.set_tracepoints(idx, body, true).is_err() {
+                    self.sockets.get::<TcpSocket>(handle).close();
+                }
+            },
+            Command::TracepointDisableRequest => {
+                if self.set_tracepoints(idx, body, false).is_err() {
+                    self.sockets.get::<TcpSocket>(handle).close();
+                }
+            },
+            _ => (), // unreachable: check_parse_header rejects anything else
+        }
+    }
+
+    fn send_tracepoint_list(&mut self, handle: SocketHandle)
+    {
+        let mut que: VecDeque<u8> = VecDeque::new();
+
+        for name in self.tracepoints.keys() {
+            for byte in (name.len() as u16).to_be_bytes().iter() {
+                que.push_back(*byte);
+            }
+            for byte in name.as_bytes() {
+                que.push_back(*byte);
+            }
+        }
+
+        protocol::push_front_header(&mut que, Command::TracepointListReply);
+        let mut socket = self.sockets.get::<TcpSocket>(handle);
+        let _ = SmolSocket(&mut socket).try_write(&protocol::flatten(&que));
+    }
+
+    // Parses a fully-buffered TracepointEnable/DisableRequest body (see
+    // `read_frame`, which guarantees `body` holds exactly `len` bytes
+    // before this is ever called -- no partial names to worry about here).
+    fn set_tracepoints(&mut self, idx: usize, body: &[u8], state: bool) -> Result<(), ()>
+    {
+        let mut read = 0usize;
+
+        while read < body.len() {
+            if read + 2 > body.len() {
+                return Err(());
+            }
+            let name_len = u16::from_be_bytes([body[read], body[read + 1]]) as usize;
+            read += 2;
+
+            if name_len > MAX_TRACEPOINT_NAME_LEN || read + name_len > body.len() {
+                return Err(());
+            }
+
+            if let Ok(name) = core::str::from_utf8(&body[read..read + name_len]) {
+                if self.tracepoints.contains_key(name) {
+                    self.sessions[idx].tracepoints.insert(String::from(name), state);
+                }
+            }
+            read += name_len;
+        }
+
+        Ok(())
+    }
+
+    // Fans the buffer out to every connected, subscribed session, same
+    // filtering rule as `tcp_handler::send_trace_data` (a session only
+    // gets the tracepoints it asked for), then drops it regardless of
+    // whether anyone was connected to read it -- there's no backpressure
+    // mechanism here yet (see FIXME on `tracy_embedded_poll`).
+    fn flush(&mut self)
+    {
+        if self.buffer.is_empty() {
+            return;
+        }
+
+        for i in 0..self.sessions.len() {
+            if !self.sessions[i].connected {
+                continue;
+            }
+
+            let mut que: VecDeque<u8> = VecDeque::new();
+            for (name, timestamp, ctx_id, data) in self.buffer.iter() {
+                if self.sessions[i].tracepoints.get(name).copied().unwrap_or(false) {
+                    protocol::encode_append_trace_data(&mut que, name, *timestamp,
+                        *ctx_id, data);
+                }
+            }
+
+            if que.is_empty() {
+                continue;
+            }
+
+            protocol::push_front_header(&mut que, Command::TracePush);
+            let handle = self.sessions[i].handle;
+            let mut socket = self.sockets.get::<TcpSocket>(handle);
+            let _ = SmolSocket(&mut socket).try_write(&protocol::flatten(&que));
+        }
+
+        self.buffer.clear();
+    }
+}
+
+// Builds the interface, a fixed pool of `MAX_SESSIONS` listening sockets,
+// and wraps them in a `SmolTracer`. Leaked (`Box::into_raw`) rather than
+// handed back by value: smoltcp's `EthernetInterface`/`SocketSet` borrow
+// the backing storage they're built from, and this is the embedded
+// equivalent of `std_runtime::tracy_init`'s `Box::into_raw` -- the C
+// caller owns the pointer for the process's lifetime and never expects
+// to get the storage back.
+//
+// FIXME: no UDP announce beacon yet (`udp_beacon` is `std`-only); a
+// collector has to be told this tracer's address out of band for now.
+#[no_mangle]
+pub extern "C" fn tracy_init_embedded(device: TracyDevice,
+                                       mac: [u8; 6],
+                                       ip: [u8; 4],
+                                       prefix_len: u8,
+                                       port: u16) -> *mut SmolTracer<'static>
+{
+    let neighbor_cache = NeighborCache::new(BTreeMap::new());
+    let ip_addr = IpCidr::new(IpAddress::v4(ip[0], ip[1], ip[2], ip[3]), prefix_len);
+
+    let iface = EthernetInterfaceBuilder::new(device)
+        .ethernet_addr(EthernetAddress(mac))
+        .neighbor_cache(neighbor_cache)
+        .ip_addrs(alloc::vec![ip_addr])
+        .finalize();
+
+    let mut sockets = SocketSet::new(Vec::new());
+    let mut sessions = Vec::with_capacity(MAX_SESSIONS);
+
+    for _ in 0..MAX_SESSIONS {
+        let rx_buf = TcpSocketBuffer::new(alloc::vec![0u8; SOCKET_BUF_LEN]);
+        let tx_buf = TcpSocketBuffer::new(alloc::vec![0u8; SOCKET_BUF_LEN]);
+        let mut socket = TcpSocket::new(rx_buf, tx_buf);
+        let _ = socket.listen(port);
+        let handle = sockets.add(socket);
+
+        sessions.push(Session {
+            handle,
+            connected: false,
+            tracepoints: BTreeMap::new(),
+            rx_scratch: Vec::new(),
+        });
+    }
+
+    let tracer = SmolTracer {
+        iface,
+        sockets,
+        sessions,
+        port,
+        buffer: VecDeque::new(),
+        tracepoints: BTreeMap::new(),
+    };
+
+    alloc::boxed::Box::into_raw(alloc::boxed::Box::new(tracer))
+}
+
+#[no_mangle]
+pub extern "C" fn tracy_register_embedded(tracey: *mut SmolTracer,
+                                           name: *const core::ffi::c_char)
+{
+    if tracey.is_null() || name.is_null() {
+        return;
+    }
+
+    unsafe {
+        let name = core::ffi::CStr::from_ptr(name).to_string_lossy();
+        (*tracey).register(&name);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tracy_submit_embedded(tracey: *mut SmolTracer,
+                                         name: *const core::ffi::c_char,
+                                         timestamp_nanos: u64,
+                                         data: *const u8,
+                                         data_len: usize)
+{
+    submit_embedded_internal(tracey, name, timestamp_nanos, 0, data, data_len);
+}
+
+
+// Like `tracy_submit_embedded`, but carries the same `ctx_id` correlation
+// id `tracy_submit_ctx` adds on the std side -- see `BufferElement::ctx_id`.
+#[no_mangle]
+pub extern "C" fn tracy_submit_ctx_embedded(tracey: *mut SmolTracer,
+                                             name: *const core::ffi::c_char,
+                                             timestamp_nanos: u64,
+                                             ctx_id: u64,
+                                             data: *const u8,
+                                             data_len: usize)
+{
+    submit_embedded_internal(tracey, name, timestamp_nanos, ctx_id, data, data_len);
+}
+
+
+fn submit_embedded_internal(tracey: *mut SmolTracer,
+                            name: *const core::ffi::c_char,
+                            timestamp_nanos: u64,
+                            ctx_id: u64,
+                            data: *const u8,
+                            data_len: usize)
+{
+    if tracey.is_null() || name.is_null() || data.is_null() || data_len == 0 {
+        return;
+    }
+
+    unsafe {
+        let name = core::ffi::CStr::from_ptr(name).to_string_lossy();
+        let slice = core::slice::from_raw_parts(data, data_len);
+        (*tracey).submit(&name, timestamp_nanos, ctx_id, slice);
+    }
+}
+
+// Drives exactly one round of the embedded event loop and returns the
+// number of microseconds the caller may sleep before calling this again
+// (0 if smoltcp wants to be polled again immediately, e.g. because it
+// still has queued work). The caller must also call this as soon as
+// the device's own receive-interrupt fires, regardless of the deadline,
+// since a new frame can arrive before it.
+//
+// FIXME: `SmolTracer::flush` has none of `tcp_handler`'s backpressure
+// handling (pending_write/high_water_mark) -- a socket with a full send
+// buffer just silently drops that flush's frame.
+#[no_mangle]
+pub extern "C" fn tracy_embedded_poll(tracey: *mut SmolTracer, now_nanos: u64) -> u64
+{
+    if tracey.is_null() {
+        return u64::max_value();
+    }
+
+    let timestamp = Instant::from_millis((now_nanos / 1_000_000) as i64);
+
+    let delay = unsafe { (*tracey).poll(timestamp) };
+
+    match delay {
+        Some(d) => d.total_micros(),
+        None => u64::max_value(),
+    }
+}
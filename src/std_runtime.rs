@@ -0,0 +1,934 @@
+// Copyright 2019, 2020 Rohde & Schwarz GmbH & Co KG
+//      philipp.stanner@rohde-schwarz.com
+//      hagen.pfeifer@rohde-schwarz.com
+
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// The `std`/mio-based tracer: event loop, C FFI surface, and the
+// concrete transports (`tcp_handler`, and optionally `quic_handler` /
+// `shm_handler`). Everything transport-agnostic lives in `crate::protocol`
+// and `crate::transport` instead, which this module's transports build on.
+
+#[path = "udp_beacon.rs"]
+mod udp_beacon;
+#[path = "tcp_handler.rs"]
+mod tcp_handler;
+#[cfg(feature = "quic")]
+#[path = "quic_handler.rs"]
+mod quic_handler;
+#[cfg(feature = "shm")]
+#[path = "shm_handler.rs"]
+mod shm_handler;
+
+extern crate mio;
+extern crate mio_extras;
+extern crate slab;
+#[cfg(feature = "quic")]
+extern crate neqo_crypto;
+#[cfg(feature = "quic")]
+extern crate neqo_transport;
+#[cfg(feature = "shm")]
+extern crate libc;
+
+use mio::*;
+use mio::net::TcpListener;
+use mio_extras::channel;
+use mio_extras::channel::{Sender, Receiver};
+use mio_extras::timer::{Timer, Timeout};
+
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// for null-pointer-generation
+use std::ptr;
+use std::str::FromStr;
+
+use std::net::{UdpSocket, SocketAddr};
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_uint};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use std::collections::{HashMap, VecDeque};
+
+static SERVER_VERSION: &str = "1.1.0";
+// Bumped for the `ctx_id` field `tracy_submit_ctx` adds to the wire frame
+// (see `protocol::encode_append_trace_data`) -- a peer still speaking 1.1.0
+// framing wouldn't know to expect the extra 8 bytes.
+static PROTOCOLL_VERSION: &str = "1.2.0";
+
+const MAX_TRACEPOINT_NAME_LEN: usize = 32;
+const MAX_SUBMIT_LEN: usize = 2048;
+
+const QUEUE_TOTAL_SIZE: usize = 4096;
+
+const TIMESTAMP_LEN: usize = 8;
+const CTX_ID_LEN: usize = 8;
+
+const QUEUE_TIMEOUT_IDENT: usize = 42;
+const UDP_TIMEOUT_IDENT: usize = 9001;
+
+const CHAN: Token = Token(1);
+const TIMER: Token = Token(2);
+const CON_NEW: Token = Token(3);
+
+// Base for the per-connection tokens `tcp_handler::establish_connection`
+// hands out: each connected client's mio `Token` is `CON_DATA_BASE + key`,
+// where `key` is its stable slot in `TracerContext::connections` (the
+// slab-of-IO-resources pattern Tokio's reactor uses). Pushed well past
+// the handful of fixed-purpose tokens above (and past
+// `quic_handler::CON_QUIC`) so accepting many TCP clients can never
+// collide with them, and packed so `event_handler` can recover a
+// connection's slab key straight from the token with no lookup.
+const CON_DATA_BASE: Token = Token(1024);
+
+// `tracy_init`'s `flags` bits.
+#[cfg(feature = "quic")]
+const FLAG_QUIC: c_int = 1 << 0;
+// Sets TCP_NODELAY on every accepted connection -- trades a few extra
+// small packets for not waiting on Nagle's algorithm, for callers who
+// care more about trace-data latency than bandwidth.
+const FLAG_TCP_NODELAY: c_int = 1 << 1;
+// Opts into the SHM ring for co-located clients. Off unless set, like
+// `FLAG_QUIC`: nothing negotiates the ring's fd to a client yet (see the
+// TODO on `ShmRing`'s `AsRawFd` impl in shm_handler), so enabling this without some out-of-band way
+// to hand that fd over just fills a 1 MiB ring nobody drains.
+#[cfg(feature = "shm")]
+const FLAG_SHM: c_int = 1 << 2;
+
+
+enum ChannelMessage {
+    Payload(BufferElement),
+    NewTracepoint(Tracepoint),
+    Terminate,
+}
+
+
+enum TracerState {
+    Normal,
+    Terminate,
+    DataProcessed,
+}
+
+
+// Handler struct passed to the C-Application
+struct TracerNg {
+    send_to_tracer_thread: Sender<ChannelMessage>,
+    client_connected: Arc<AtomicBool>,
+    tracepoints: HashMap<String, Arc<AtomicBool>>,
+    // Bytes of not-yet-sent `BufferElement`s shed by
+    // `TracerContext::shed_oldest_buffered` because some connection's own
+    // write backlog crossed `high_water_mark`. Shared with the tracer
+    // thread the same way `client_connected` is, so `tracy_stats` can
+    // read it from the caller's thread without a round-trip through the
+    // channel.
+    dropped_bytes: Arc<AtomicU64>,
+}
+
+// structuring a new tracepoint to be inserted
+struct Tracepoint {
+    name: String,
+    state: Arc<AtomicBool>,
+}
+
+
+// Used to capsule data from init() for tracer-thread
+// The app-user is allowed to choose a default interface by passing NULL
+struct InitData {
+    hostname: String,
+    process_name: String,
+    send_interval: Duration,
+    announce_interval: Duration,
+    announce_addr: Option<SocketAddr>,
+    announce_iface: Option<String>,
+
+    // Overrides for what the UDP announce JSON tells clients to dial,
+    // instead of the hostname and `listener.local_addr()`'s port -- needed
+    // whenever the tracer sits behind NAT, a container port mapping, or
+    // is only reachable via a public DNS name. Empty/`None` means
+    // "advertise the hostname and the port we're actually bound to", same
+    // as before this was configurable.
+    advertise_addresses: Vec<String>,
+    advertise_port: Option<u16>,
+
+    // Called (with the current retained-but-unsent byte count) after every
+    // drain attempt that leaves a connection's outbound buffer above
+    // `high_water_mark`, so
+    // the traced application can shed load itself instead of relying on
+    // us to buffer indefinitely. `None` if the caller didn't register one.
+    high_water_mark: usize,
+    on_backpressure: Option<extern "C" fn(c_uint)>,
+
+    // Socket tuning applied to every accepted TCP connection in
+    // `tcp_handler::establish_connection`. `tcp_keepalive` is the
+    // interval passed to `SO_KEEPALIVE`, `None` leaves it off; a
+    // half-open peer that never ACKs the keepalive probes eventually
+    // surfaces as a read/write error, closing the session the same way
+    // any other socket error does.
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<Duration>,
+
+    // If a connection with unsent data goes this long without a single
+    // successful write, `timer_handler` closes it on the next
+    // QUEUE_TIMEOUT_IDENT tick -- see `tcp_handler::close_stale_connections`
+    // and `ClientSession::backlog_since`. `None` disables the check (a
+    // connection can then sit half-open against a dead peer forever).
+    write_timeout: Option<Duration>,
+
+    // QUIC is an optional peer of the plain-TCP transport; when disabled
+    // (the default) the fields below are simply never read.
+    #[cfg(feature = "quic")]
+    quic_enabled: bool,
+    #[cfg(feature = "quic")]
+    quic_cert_path: Option<String>,
+    #[cfg(feature = "quic")]
+    quic_key_path: Option<String>,
+
+    // See FLAG_SHM -- defaults to off even with the `shm` feature compiled
+    // in, since there's no fd-negotiation path to a client yet.
+    #[cfg(feature = "shm")]
+    shm_enabled: bool,
+}
+
+// structures data from application in submit-function: tracepoint name,
+// associated data and a timestamp when the data was submitted.
+// Enqueued in tracer-thread, later serialized and sent over TCP
+struct BufferElement {
+    tracepoint: String,
+    timestamp: SystemTime,
+    // Caller-supplied correlation id from `tracy_submit_ctx` (0 for
+    // submissions made through the plain `tracy_submit` wrapper), echoed
+    // into the wire frame so downstream tooling can group tracepoints
+    // belonging to the same logical request without parsing payloads.
+    ctx_id: u64,
+    data: Vec<u8>,
+}
+
+impl BufferElement {
+    fn len(&self) -> usize
+    {
+        self.tracepoint.len() + TIMESTAMP_LEN + CTX_ID_LEN + self.data.len()
+    }
+}
+
+
+struct TracerContext {
+    app_cfg: InitData,
+    poll: Poll,
+    buffer: VecDeque<BufferElement>,
+    buffer_occupancy: usize,
+
+    rec: Receiver<ChannelMessage>,
+
+    timer: Timer<usize>,
+    queue_timeout: Option<Timeout>,
+    udp_timeout: Option<Timeout>,
+
+    udp_sock: Option<UdpSocket>,
+    listener: TcpListener,
+    // One entry per connected collector, keyed by the slot `establish_
+    // connection` reserved for it; see `tcp_handler::ClientSession`.
+    connections: slab::Slab<tcp_handler::ClientSession>,
+    #[cfg(feature = "quic")]
+    quic: Option<quic_handler::QuicTransport>,
+    // Set at startup when FLAG_SHM is passed (see `shm_handler::init`);
+    // no client negotiates the segment yet.
+    #[cfg(feature = "shm")]
+    shm: Option<shm_handler::ShmRing>,
+    // TODO: Check if just checking the Hashmap is faster
+    client_connected: Arc<AtomicBool>,
+    tracepoints: HashMap<String, Arc<AtomicBool>>,
+    sequence_no: u64,
+    dropped_bytes: Arc<AtomicU64>,
+}
+
+impl TracerContext {
+    fn append(&mut self, element: BufferElement)
+    {
+        self.buffer_occupancy += element.len();
+        self.buffer.push_back(element);
+    }
+
+    #[allow(dead_code)]
+    fn clear_buffer(&mut self)
+    {
+        self.buffer.clear();
+        self.buffer_occupancy = 0;
+    }
+
+    fn check_start_queue_timer(&mut self)
+    {
+        if self.queue_timeout.is_none() {
+            self.queue_timeout =
+                Some(self.timer.set_timeout(self.app_cfg.send_interval,
+                                            QUEUE_TIMEOUT_IDENT));
+        }
+    }
+
+    fn check_stop_queue_timer(&mut self)
+    {
+        // TODO: Find out why the hell the timer wants to move the timeout,
+        // despite only having a reference as parameter
+        let tmp = self.queue_timeout.clone();
+        if self.queue_timeout.is_some() {
+            self.timer.cancel_timeout(&tmp.unwrap());
+        }
+
+        self.queue_timeout = None;
+    }
+
+    fn check_start_udp_timer(&mut self)
+    {
+        if self.udp_timeout.is_none() {
+            self.udp_timeout =
+                Some(self.timer.set_timeout(self.app_cfg.announce_interval,
+                                            UDP_TIMEOUT_IDENT));
+        }
+    }
+
+    // Stops the periodic buffer flush once the last connected TCP client is
+    // gone (nothing left to flush to over this transport); called from
+    // `tcp_handler::close_session` once `connections` is empty. Unlike the
+    // single-connection predecessor of this method, the UDP announce
+    // beacon is deliberately left running regardless of connection count,
+    // so a second collector can still discover the tracer after the first
+    // one has already attached.
+    fn stop_queue_timer_if_idle(&mut self)
+    {
+        if self.connections.is_empty() {
+            self.check_stop_queue_timer();
+        }
+    }
+
+    fn insert_tracepoint(&mut self, tracepoint: Tracepoint)
+    {
+        self.tracepoints.insert(tracepoint.name, tracepoint.state);
+    }
+
+    // True once there's somewhere `flush_trace_data` could actually send
+    // to -- it fans out to whichever of TCP/QUIC/SHM is active, not just
+    // the TCP slab, so a Terminate flush gated on `connections` alone
+    // would drop a QUIC- or SHM-only run's last batch.
+    fn has_any_destination(&self) -> bool
+    {
+        if !self.connections.is_empty() {
+            return true;
+        }
+
+        #[cfg(feature = "quic")]
+        {
+            if self.quic.as_ref().map(|q| !q.peers.is_empty()).unwrap_or(false) {
+                return true;
+            }
+        }
+
+        #[cfg(feature = "shm")]
+        {
+            if self.shm.is_some() {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // Pops the oldest buffered submissions until at least `min_bytes`
+    // worth have been shed (or `buffer` runs dry), to cap memory growth
+    // when a connection's retained write backlog shows it can't keep up.
+    // See `tcp_handler::drain_pending_write`, the only caller.
+    fn shed_oldest_buffered(&mut self, min_bytes: usize)
+    {
+        let mut shed = 0usize;
+
+        while shed < min_bytes {
+            match self.buffer.pop_front() {
+                Some(elem) => {
+                    let len = elem.len();
+                    self.buffer_occupancy = self.buffer_occupancy.saturating_sub(len);
+                    shed += len;
+                },
+                None => break,
+            }
+        }
+
+        if shed > 0 {
+            self.dropped_bytes.fetch_add(shed as u64, Ordering::SeqCst);
+        }
+    }
+}
+
+
+#[no_mangle]
+extern "C" fn tracy_init(hostname: *const c_char,
+                         process_name: *const c_char,
+                         buffer_flush_interval: c_uint, //ms
+                         announce_interval: c_uint, //ms
+                         announce_iface: *const c_char,
+                         announce_mcast_addr: *const c_char,
+                         flags: c_int,
+                         high_water_mark: c_uint, // bytes, 0 disables the callback
+                         on_backpressure: Option<extern "C" fn(c_uint)>,
+                         advertise_addresses: *const c_char, // comma-separated, NULL/empty to auto-detect
+                         advertise_port: c_uint, // 0 to keep the bound port
+                         keepalive_interval_ms: c_uint, // 0 disables SO_KEEPALIVE
+                         write_timeout_ms: c_uint, // 0 disables dead-peer detection
+                         #[cfg(feature = "quic")]
+                         quic_cert_path: *const c_char, // NULL to use a self-signed cert
+                         #[cfg(feature = "quic")]
+                         quic_key_path: *const c_char)
+                         -> *const TracerNg
+{
+    let mut announce = false;
+    #[cfg(feature = "quic")]
+    let quic_enabled = flags & FLAG_QUIC != 0;
+    #[cfg(not(feature = "quic"))]
+    let _ = flags; // Only the `quic` feature interprets the FLAG_QUIC bit today.
+    #[cfg(feature = "shm")]
+    let shm_enabled = flags & FLAG_SHM != 0;
+    let tcp_nodelay = flags & FLAG_TCP_NODELAY != 0;
+    let is_null = hostname.is_null() || process_name.is_null() ||
+                    buffer_flush_interval == 0;
+    if is_null {
+        return ptr::null();
+    }
+
+    // There can't be a client connected yet
+    let client_connected_thr = Arc::new(AtomicBool::new(false));
+    let client_connected_ret = Arc::clone(&client_connected_thr);
+    let dropped_bytes_thr = Arc::new(AtomicU64::new(0));
+    let dropped_bytes_ret = Arc::clone(&dropped_bytes_thr);
+    let (snd, rec): (Sender<ChannelMessage>, Receiver<ChannelMessage>) =
+                     channel::channel();
+
+    let init_data = InitData {
+        hostname: rawpt_to_str(hostname)
+            .expect("tracy: hostname broken."),
+        process_name: rawpt_to_str(process_name)
+            .expect("tracy: process_name broken"),
+        send_interval: Duration::from_millis(buffer_flush_interval as u64),
+        announce_interval:
+            Duration::from_millis(announce_interval as u64),
+        announce_iface: rawpt_to_str(announce_iface),
+        announce_addr: rawpt_to_addr(announce_mcast_addr),
+        advertise_addresses: rawpt_to_str(advertise_addresses)
+            .map(|s| s.split(',').map(|a| a.trim().to_string())
+                .filter(|a| !a.is_empty()).collect())
+            .unwrap_or_default(),
+        advertise_port: if advertise_port == 0 { None } else { Some(advertise_port as u16) },
+        high_water_mark: high_water_mark as usize,
+        on_backpressure,
+        tcp_nodelay,
+        tcp_keepalive: if keepalive_interval_ms == 0 { None }
+            else { Some(Duration::from_millis(keepalive_interval_ms as u64)) },
+        write_timeout: if write_timeout_ms == 0 { None }
+            else { Some(Duration::from_millis(write_timeout_ms as u64)) },
+        #[cfg(feature = "quic")]
+        quic_enabled,
+        #[cfg(feature = "quic")]
+        quic_cert_path: rawpt_to_str(quic_cert_path),
+        #[cfg(feature = "quic")]
+        quic_key_path: rawpt_to_str(quic_key_path),
+        #[cfg(feature = "shm")]
+        shm_enabled,
+    };
+
+    let tracey = TracerNg {
+        send_to_tracer_thread: snd,
+        client_connected: client_connected_ret,
+        tracepoints: HashMap::with_capacity(256),
+        dropped_bytes: dropped_bytes_ret,
+    };
+
+    if announce_interval > 0 && init_data.announce_iface.is_some() &&
+        init_data.announce_addr.is_some() {
+        announce = true;
+    }
+
+    thread::spawn(move | | tracer_thread_main(init_data, client_connected_thr,
+                                              dropped_bytes_thr, rec, announce));
+    // Place the struct on the heap and give control to a raw pointer
+    Box::into_raw(Box::new(tracey))
+}
+
+
+fn rawpt_to_addr(cstring: *const c_char) -> Option<SocketAddr>
+{
+    let s: String = rawpt_to_str(cstring)?;
+    string_to_addr(s)
+}
+
+
+fn rawpt_to_str(cstring: *const c_char) -> Option<String>
+{
+    if cstring.is_null() {
+        return None;
+    }
+
+    let s: String;
+    unsafe {
+        s = CStr::from_ptr(cstring).to_string_lossy().into_owned();
+    };
+
+    Some(s)
+}
+
+
+fn string_to_addr(s: String) -> Option<SocketAddr>
+{
+    match SocketAddr::from_str(&s[..]) {
+        Ok(addr) => {
+            Some(addr)
+        },
+        Err(e) => {
+            eprint!("tracy: Could not resolve user addr.: {}", e);
+            None
+        },
+    }
+}
+
+
+#[no_mangle]
+extern "C" fn tracy_register(tracy: *mut TracerNg,
+                                 tp_name_param: *const c_char) -> c_int
+{
+    let tracey: &mut TracerNg;
+    let tracepoint: Tracepoint;
+    let tp_name: String;
+    let tracepoint_state = Arc::new(AtomicBool::new(false));
+
+    if tracy.is_null() {
+        eprintln!("tracy_register: Received NULL-Pointer. Ignoring request.");
+        return -1;
+    }
+
+    unsafe {
+        tracey = &mut *tracy;
+        tp_name = CStr::from_ptr(tp_name_param).to_string_lossy().into_owned();
+    }
+
+    let tp_name_repaired = match fix_tracepoint_str(tp_name) {
+        Ok(x) => x,
+        _ => return -1,
+    };
+
+    tracepoint = Tracepoint {
+        name: tp_name_repaired.clone(),
+        state: Arc::clone(&tracepoint_state),
+    };
+
+    if !tracey.tracepoints.contains_key(&tp_name_repaired) {
+        tracey.tracepoints.insert(tp_name_repaired, tracepoint_state);
+        let msg = ChannelMessage::NewTracepoint(tracepoint);
+        send_to_tracer(&tracey, msg);
+        0
+    } else {
+        eprintln!("tracy_register: Tracepoint already registered.");
+        -1
+    }
+}
+
+
+// FIXME Rusts os::raw does not contain the C-bool type.
+#[no_mangle]
+extern "C" fn tracy_tracepoint_enabled(tracy: *const TracerNg,
+                                           tp_name_param: *const c_char) -> bool
+{
+    let tracey: &TracerNg;
+    let tp_name: String;
+
+    unsafe {
+        tracey = &*tracy;
+        tp_name = CStr::from_ptr(tp_name_param).to_string_lossy().into_owned();
+    }
+
+    tracepoint_enabled(&tracey, &tp_name)
+}
+
+
+// Out-parameter struct rather than a return value, so this can grow more
+// counters later without breaking the C ABI of existing callers.
+#[repr(C)]
+struct TracyStats {
+    // Bytes of not-yet-sent BufferElements shed because some connection's
+    // retained write backlog crossed `high_water_mark`. See
+    // `TracerContext::shed_oldest_buffered`.
+    dropped_bytes: u64,
+}
+
+#[no_mangle]
+extern "C" fn tracy_stats(tracey: *const TracerNg, stats: *mut TracyStats) -> c_int
+{
+    if tracey.is_null() || stats.is_null() {
+        eprintln!("tracy_stats: Received NULL-pointer. Ignoring request.");
+        return -1;
+    }
+
+    unsafe {
+        (*stats).dropped_bytes = (*tracey).dropped_bytes.load(Ordering::SeqCst);
+    }
+
+    0
+}
+
+
+#[no_mangle]
+extern "C" fn tracy_finit(tracey: *mut TracerNg)
+{
+    let tracer: TracerNg;
+    // Box takes ownership and deallocates the heap-located TracerNg struct
+    // when going out of scope, including the Arc<AtomicBool>
+    tracer = unsafe{ *Box::from_raw(tracey) };
+
+    send_to_tracer(&tracer, ChannelMessage::Terminate);
+}
+
+
+// TODO:
+// submit checks de facto two times if the client is conncted: Once with
+// the AtomicBool client_connected, later again by looking in the HashMap if the
+// tracepoint is activated. Maybe only checking the HashMap is better
+#[no_mangle]
+extern "C" fn tracy_submit(tmp_tracey: *const TracerNg,
+                               tp_name_param: *const c_char,
+                               data: *const u8,
+                               data_len: usize)
+{
+    submit_internal(tmp_tracey, tp_name_param, data, data_len, 0);
+}
+
+
+// Like `tracy_submit`, but lets the caller attach an opaque `ctx_id` that's
+// echoed back in the wire frame unchanged (see `BufferElement::ctx_id` and
+// `protocol::encode_append_trace_data`), mirroring the pattern of a
+// caller-supplied handle the library threads through every interaction
+// without interpreting it -- so analysts can stitch together tracepoints
+// belonging to the same logical request or transaction without parsing
+// payloads.
+#[no_mangle]
+extern "C" fn tracy_submit_ctx(tmp_tracey: *const TracerNg,
+                               tp_name_param: *const c_char,
+                               data: *const u8,
+                               data_len: usize,
+                               ctx_id: u64)
+{
+    submit_internal(tmp_tracey, tp_name_param, data, data_len, ctx_id);
+}
+
+
+fn submit_internal(tmp_tracey: *const TracerNg,
+                   tp_name_param: *const c_char,
+                   data: *const u8,
+                   data_len: usize,
+                   ctx_id: u64)
+{
+    let tracey: &TracerNg;
+    let buffer_element: BufferElement;
+    let tracepoint: String;
+
+    if tmp_tracey.is_null() || tp_name_param.is_null() || data.is_null() {
+        eprintln!("tracy_submit: Received NULL-pointer. Ignoring request.");
+        return;
+    }
+
+    if data_len == 0 || data_len > MAX_SUBMIT_LEN {
+        eprintln!("tracy_submit: Invalid data_length. Ignoring request.");
+        return;
+    }
+
+    // Don't pack raw pointer in a Box, otherwise the memory of tmp_tracey
+    // would get deallocated when submit returns.
+    tracey = unsafe{&*tmp_tracey};
+    if !tracey.client_connected.load(Ordering::SeqCst) {
+        return;
+    }
+
+    unsafe {
+        tracepoint = CStr::from_ptr(tp_name_param)
+            .to_string_lossy().into_owned();
+    }
+
+    let tracepoint_repaired = match fix_tracepoint_str(tracepoint) {
+        Ok(x) => x,
+        _ => {
+            eprintln!("tracy_submit: Tracepoint-String broken. Ignoring.");
+            return;
+        },
+    };
+
+    if !tracepoint_enabled(&tracey, &tracepoint_repaired) {
+        return;
+    }
+
+    unsafe {
+        buffer_element = BufferElement {
+            tracepoint: tracepoint_repaired.clone(),
+            timestamp: SystemTime::now(),
+            ctx_id,
+            data: std::slice::from_raw_parts(data, data_len).to_vec(),
+        };
+    }
+
+    let msg = ChannelMessage::Payload(buffer_element);
+    send_to_tracer(&tracey, msg);
+}
+
+
+fn tracepoint_enabled(tracey: &TracerNg, tracepoint: &String) -> bool
+{
+    match tracey.tracepoints.get(tracepoint) {
+        Some(truth) => truth.load(Ordering::SeqCst),
+        None => false,
+    }
+}
+
+
+fn send_to_tracer(tracey: &TracerNg, chan_msg: ChannelMessage)
+{
+    if let Err(e) = tracey.send_to_tracer_thread.send(chan_msg) {
+        eprintln!("tracy: Failed to send message to tracer-thread: {:?}", e);
+    }
+}
+
+
+fn fix_tracepoint_str(mut tracepoint: String) -> Result<String, ()>
+{
+    if !tracepoint.is_ascii() {
+        eprintln!("tracy: tracepoint is not ascii. Ignoring request.");
+        return Err(());
+    }
+
+    if tracepoint.len() > MAX_TRACEPOINT_NAME_LEN {
+        eprintln!("tracy: tracepoint-ID-String too long. Limiting to {} chars",
+                MAX_TRACEPOINT_NAME_LEN);
+        tracepoint.truncate(MAX_TRACEPOINT_NAME_LEN);
+    }
+
+    Ok(tracepoint.to_lowercase())
+}
+
+
+fn tracer_thread_main(app_cfg_data: InitData,
+                      client_connected_in: Arc<AtomicBool>,
+                      dropped_bytes_in: Arc<AtomicU64>,
+                      rec_param: Receiver<ChannelMessage>,
+                      announce: bool)
+{
+    let mut events = Events::with_capacity(1024);
+    let udp_iface = app_cfg_data.announce_iface.clone();
+
+    let mut ctx = TracerContext {
+        app_cfg: app_cfg_data,
+        poll: Poll::new().expect("tracy: Poll creation"),
+        // 'buffer' is holding the structs "BufferElement"
+        buffer: VecDeque::with_capacity(1024),
+        timer: Timer::default(),
+        rec: rec_param,
+        queue_timeout: None,
+        udp_timeout: None,
+        buffer_occupancy: 0,
+        udp_sock: None,
+        listener: tcp_handler::init()
+            .expect("tracy: Could not bind TCP socket."),
+        connections: slab::Slab::new(),
+        #[cfg(feature = "quic")]
+        quic: None,
+        #[cfg(feature = "shm")]
+        shm: None,
+        client_connected: client_connected_in,
+        tracepoints: HashMap::with_capacity(128),
+        sequence_no: 0,
+        dropped_bytes: dropped_bytes_in,
+    };
+
+    // If the parameters given by the caller indicate that he wishes
+    // UDP announcing, try to bind a socket and start announcing
+    if announce {
+        ctx.udp_sock = match udp_beacon::init(udp_iface) {
+            Ok(sock) => Some(sock),
+            Err(e) => {
+                eprintln!("Could not bind udp sock: {}", e);
+                None
+            },
+        };
+        ctx.check_start_udp_timer();
+    }
+
+    #[cfg(feature = "shm")]
+    {
+        if ctx.app_cfg.shm_enabled {
+            ctx.shm = match shm_handler::init() {
+                Ok(ring) => Some(ring),
+                Err(e) => {
+                    eprintln!("tracy: Could not set up SHM ring, local clients \
+                               fall back to TCP: {}", e);
+                    None
+                },
+            };
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    {
+        if ctx.app_cfg.quic_enabled {
+            let bind_addr = SocketAddr::new(
+                std::net::IpAddr::V6(std::net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0);
+            ctx.quic = quic_handler::init(bind_addr,
+                ctx.app_cfg.quic_cert_path.clone(),
+                ctx.app_cfg.quic_key_path.clone());
+            if let Some(quic) = &ctx.quic {
+                ctx.poll.register(&quic.sock, quic_handler::CON_QUIC,
+                    Ready::readable(), PollOpt::edge())
+                    .expect("tracy: Panicked at registering QUIC socket in poll.");
+            }
+        }
+    }
+
+    ctx.poll.register(&ctx.rec, CHAN, Ready::readable(), PollOpt::edge())
+        .expect("tracy: Panicked at registering channel in poll.");
+    ctx.poll.register(&ctx.timer, TIMER, Ready::readable(), PollOpt::edge())
+        .expect("tracy: Panicked at registering timer in poll.");
+    ctx.poll.register(&ctx.listener, CON_NEW, Ready::readable(), PollOpt::edge())
+        .expect("tracy: Panicked at registering TcpListener in poll.");
+
+    loop {
+        ctx.poll.poll(&mut events, None).expect("tracy: Panicked in poll.");
+
+        if let TracerState::Terminate = event_handler(&events, &mut ctx) {
+            return;
+        }
+    }
+}
+
+
+// FIXME: Error handling & return of handler-functions. Especially channel-handler
+// signals with its state when main shall terminate. Find a more rusty solution
+fn event_handler(events: &Events,
+                  mut ctx: &mut TracerContext) -> TracerState
+{
+    let mut ret = TracerState::Normal;
+
+    for event in events.iter() {
+        match event.token() {
+            CHAN => match channel_handler(&mut ctx) {
+                TracerState::Terminate =>
+                    return TracerState::Terminate,
+                state => ret = state,
+            },
+            TIMER => timer_handler(&mut ctx),
+            // No single-connection gate anymore: every accept gets its own
+            // session so several collectors can be attached at once.
+            CON_NEW => tcp_handler::establish_connection(&mut ctx),
+            #[cfg(feature = "quic")]
+            quic_handler::CON_QUIC => quic_handler::receive(&mut ctx),
+            token => if let Some(key) = tcp_handler::key_for_token(&ctx, token) {
+                if event.readiness().is_writable() {
+                    tcp_handler::drain_pending_write(&mut ctx, key);
+                }
+                if event.readiness().is_readable() {
+                    tcp_handler::receive(&mut ctx, key);
+                }
+            },
+        }
+    }
+
+    ret
+}
+
+
+// Dispatches a buffer flush to whichever transport is currently active.
+// They are peers of each other, not simultaneous consumers of the same
+// buffer, so exactly one of them drains `ctx.buffer` per flush.
+//
+// SHM has no fd-negotiation path to a client yet (see FLAG_SHM and the
+// TODO on `ShmRing`'s `AsRawFd` impl in shm_handler), so it's opt-in and,
+// even then, only used while no TCP/QUIC client is connected, so it never
+// takes data away from a remote analyzer. When QUIC is enabled it takes
+// every flush SHM doesn't, so TCP clients get no TracePush data at all then.
+fn flush_trace_data(ctx: &mut TracerContext)
+{
+    #[cfg(feature = "shm")]
+    {
+        #[cfg(feature = "quic")]
+        let no_quic_peers = ctx.quic.as_ref().map(|q| q.peers.is_empty()).unwrap_or(true);
+        #[cfg(not(feature = "quic"))]
+        let no_quic_peers = true;
+
+        if ctx.shm.is_some() && ctx.connections.is_empty() && no_quic_peers {
+            shm_handler::send_trace_data(ctx);
+            return;
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    {
+        if ctx.quic.is_some() {
+            quic_handler::send_trace_data(ctx);
+            return;
+        }
+    }
+
+    tcp_handler::send_trace_data(ctx);
+}
+
+
+fn channel_handler(mut ctx: &mut TracerContext) -> TracerState
+{
+    let mut ret = TracerState::Normal;
+
+    while let Ok(data) = ctx.rec.try_recv() {
+        match data {
+            ChannelMessage::Payload(payload) =>
+                channel_data_handler(&mut ctx, payload),
+            ChannelMessage::NewTracepoint(tracepoint) =>
+                ctx.insert_tracepoint(tracepoint),
+            ChannelMessage::Terminate => {
+                // Send remaining data one last time before killing thread
+                if ctx.has_any_destination() {
+                    flush_trace_data(&mut ctx);
+                }
+                return TracerState::Terminate;
+            },
+        }
+        ret = TracerState::DataProcessed;
+    }
+
+    ret
+}
+
+
+fn timer_handler(mut ctx: &mut TracerContext)
+{
+    while let Some(timeout) = ctx.timer.poll() {
+        match timeout {
+            QUEUE_TIMEOUT_IDENT => {
+                ctx.queue_timeout = None;
+                tcp_handler::close_stale_connections(&mut ctx);
+                flush_trace_data(&mut ctx);
+            },
+            UDP_TIMEOUT_IDENT => {
+                ctx.udp_timeout = None;
+                let _ = udp_beacon::announce_tracer(&mut ctx);
+                ctx.check_start_udp_timer();
+            },
+            _ => (),
+        }
+    }
+}
+
+
+fn channel_data_handler(mut ctx: &mut TracerContext, data: BufferElement)
+{
+    // Append data in any case, as it is already allocated.
+    ctx.append(data);
+
+    if ctx.buffer_occupancy > QUEUE_TOTAL_SIZE {
+        ctx.check_stop_queue_timer();
+        flush_trace_data(&mut ctx);
+    } else {
+        ctx.check_start_queue_timer();
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright 2019, 2020 Rohde & Schwarz GmbH & Co KG
 //      philipp.stanner@rohde-schwarz.com
 //      hagen.pfeifer@rohde-schwarz.com
-//
+
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
@@ -12,28 +12,47 @@ use mio::net::{TcpListener, TcpStream};
 use std::net::{SocketAddr, IpAddr, Ipv6Addr};
 use std::io::{ErrorKind, BufReader, Read, Write};
 use std::sync::atomic::Ordering;
+use std::collections::HashMap;
 
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use std::collections::VecDeque;
 
-use crate::{TracerContext, BufferElement, CON_DATA, QUEUE_TOTAL_SIZE,
+use crate::std_runtime::{TracerContext, BufferElement, CON_DATA_BASE, QUEUE_TOTAL_SIZE,
             MAX_TRACEPOINT_NAME_LEN};
+use crate::protocol::{self, Command};
 
-pub const HEADER_LEN: usize = 12;
+pub use crate::protocol::{HEADER_LEN, MAGIC_NUMB};
 
-// magic nr: 'RuSt'
-pub const MAGIC_NUMB: [u8; 4] = [0x52, 0x75, 0x53, 0x74];
 const REC_BUF_SZ: usize = 4096;
 
-#[repr(u16)]
-enum Command {
-    TracepointListRequest       = 1,
-    TracepointListReply         = 2,
-    TracepointEnableRequest     = 3,
-    TracepointDisableRequest    = 4,
-    TracePush                   = 5,
-    Invalid                     = 42,
+
+// One connected collector, owned by `TracerContext::connections`'s slab
+// slot. Each gets its own outbound queue and its own view of which
+// tracepoints it subscribed to, so e.g. a live dashboard and a file
+// recorder can enable different tracepoints without fighting over a
+// shared socket; `sequence_no` counts the TracePush batches sent to this
+// client specifically.
+pub(crate) struct ClientSession {
+    stream: TcpStream,
+    token: Token,
+    // Unsent tail retained across a WouldBlock; drained on the next
+    // writable-readiness event instead of being discarded.
+    pending_write: VecDeque<u8>,
+    tracepoints: HashMap<String, bool>,
+    sequence_no: u64,
+    // When the current pending_write backlog became non-empty or last had
+    // bytes written out, or `None` while it's empty -- idle is never
+    // stale, only a backlog stuck past
+    // `app_cfg.write_timeout` is (`close_stale_connections`).
+    backlog_since: Option<SystemTime>,
+}
+
+impl ClientSession {
+    fn tracepoint_enabled(&self, name: &str) -> bool
+    {
+        self.tracepoints.get(name).copied().unwrap_or(false)
+    }
 }
 
 
@@ -57,35 +76,203 @@ pub(crate) fn init() -> Option<TcpListener>
 }
 
 
-pub(crate) fn establish_connection(mut ctx: &mut TracerContext)
+// Accepts one more collector without evicting anyone already connected.
+// Reserves the session's slab slot up front so its mio `Token`
+// (`CON_DATA_BASE + key`) is known before the session itself is built,
+// the same two-step vacant-entry dance Tokio's reactor uses to pack a
+// small integer into the token it registers with epoll/kqueue.
+pub(crate) fn establish_connection(ctx: &mut TracerContext)
 {
     match ctx.listener.accept() {
         Ok((socket, _addr)) => {
+            if let Err(e) = socket.set_nodelay(ctx.app_cfg.tcp_nodelay) {
+                eprintln!("tracy: Could not configure TCP_NODELAY: {}", e);
+            }
+            if let Err(e) = socket.set_keepalive(ctx.app_cfg.tcp_keepalive) {
+                eprintln!("tracy: Could not configure SO_KEEPALIVE: {}", e);
+            }
+
             let temp_con = socket.try_clone().unwrap();
-            ctx.connection = Some(socket);
-            ctx.client_connected.store(true, Ordering::SeqCst);
+            let entry = ctx.connections.vacant_entry();
+            let token = Token(CON_DATA_BASE.0 + entry.key());
+
             ctx.poll.register(&temp_con,
-                CON_DATA,
-                Ready::readable(),
+                token,
+                Ready::readable() | Ready::writable(),
                 PollOpt::edge())
                 .expect("Panicked at registering socket in poll.");
+
+            entry.insert(ClientSession {
+                stream: socket,
+                token,
+                pending_write: VecDeque::new(),
+                tracepoints: HashMap::new(),
+                sequence_no: 0,
+                backlog_since: None,
+            });
+            ctx.client_connected.store(true, Ordering::SeqCst);
         },
         Err(_) => eprintln!("tracy: Could not establish connection."),
     }
 }
 
 
-pub(crate) fn receive(mut ctx: &mut TracerContext)
+// Closes any connection whose backlog has sat unsent for longer than
+// `app_cfg.write_timeout` -- a dead or wedged peer that neither
+// TCP_NODELAY nor SO_KEEPALIVE caught. Called from `timer_handler` on
+// every QUEUE_TIMEOUT_IDENT tick.
+pub(crate) fn close_stale_connections(ctx: &mut TracerContext)
+{
+    let timeout = match ctx.app_cfg.write_timeout {
+        Some(timeout) => timeout,
+        None => return,
+    };
+
+    let stale: Vec<usize> = ctx.connections.iter()
+        .filter(|(_, session)| session.backlog_since
+            .and_then(|since| since.elapsed().ok())
+            .map(|elapsed| elapsed > timeout)
+            .unwrap_or(false))
+        .map(|(key, _)| key)
+        .collect();
+
+    for key in stale {
+        eprintln!("tracy: Closing connection with no successful write for \
+                   longer than the configured write timeout.");
+        close_session(ctx, key);
+    }
+}
+
+
+// Recovers a connection's slab key straight from the token it fired an
+// event on -- no lookup needed, since `establish_connection` packed the
+// key into the token in the first place.
+pub(crate) fn key_for_token(ctx: &TracerContext, token: Token) -> Option<usize>
+{
+    if token.0 < CON_DATA_BASE.0 {
+        return None;
+    }
+
+    let key = token.0 - CON_DATA_BASE.0;
+    if ctx.connections.contains(key) { Some(key) } else { None }
+}
+
+
+// Removes one client's slab entry: deregisters its socket, then
+// recomputes each tracepoint's aggregate enabled-flag (the one
+// `tracy_submit` actually checks) now that a client which may have been
+// the last one subscribed to it is gone. Idempotent, since several call
+// sites along one read/write pass can each hit an error on the same
+// already-closing session.
+fn close_session(ctx: &mut TracerContext, key: usize)
+{
+    let session = match ctx.connections.get(key) {
+        Some(session) => session,
+        None => return,
+    };
+
+    if let Ok(tmp) = session.stream.try_clone() {
+        let _ = ctx.poll.deregister(&tmp);
+    }
+
+    ctx.connections.remove(key);
+
+    if ctx.connections.is_empty() {
+        ctx.client_connected.store(false, Ordering::SeqCst);
+        ctx.stop_queue_timer_if_idle();
+    }
+
+    for (name, flag) in ctx.tracepoints.iter() {
+        let any_enabled = ctx.connections.iter()
+            .any(|(_, session)| session.tracepoint_enabled(name));
+        flag.store(any_enabled, Ordering::SeqCst);
+    }
+}
+
+
+// Drains as much of the session's pending_write as the socket currently
+// accepts. Called both right after queueing new data and on
+// writable-readiness events, so a backlog left over from a `WouldBlock`
+// gets flushed as soon as the peer starts accepting data again instead of
+// being dropped.
+pub(crate) fn drain_pending_write(ctx: &mut TracerContext, key: usize)
+{
+    loop {
+        let session = match ctx.connections.get_mut(key) {
+            Some(session) => session,
+            None => return,
+        };
+
+        if session.pending_write.is_empty() {
+            break;
+        }
+
+        let (first, second) = session.pending_write.as_slices();
+        let mut chunk = Vec::with_capacity(session.pending_write.len());
+        chunk.extend_from_slice(first);
+        chunk.extend_from_slice(second);
+
+        let write_result = session.stream.write(&chunk);
+
+        match write_result {
+            Ok(written) => {
+                session.pending_write.drain(..written);
+                if written == 0 {
+                    break;
+                }
+                session.backlog_since = Some(SystemTime::now());
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(_) => {
+                close_session(ctx, key);
+                return;
+            },
+        }
+    }
+
+    let session = match ctx.connections.get_mut(key) {
+        Some(session) => session,
+        None => return,
+    };
+
+    if session.pending_write.is_empty() {
+        session.backlog_since = None;
+    }
+
+    if let Some(hwm) = Some(ctx.app_cfg.high_water_mark).filter(|h| *h > 0) {
+        if session.pending_write.len() <= hwm {
+            return;
+        }
+        if let Some(cb) = ctx.app_cfg.on_backpressure {
+            cb(session.pending_write.len() as std::os::raw::c_uint);
+        }
+
+        // This connection can't keep up; shed the oldest not-yet-sent
+        // submissions so memory stays capped. Drops data for every
+        // connection, not just the slow one -- `buffer` has no
+        // per-connection view before it's encoded.
+        ctx.shed_oldest_buffered(hwm);
+    }
+}
+
+
+pub(crate) fn receive(ctx: &mut TracerContext, key: usize)
 {
-    let mut reader = BufReader::with_capacity(REC_BUF_SZ,
-                                              ctx.connection.as_mut().unwrap()
-                                              .try_clone().unwrap());
+    let stream_clone = match ctx.connections.get_mut(key) {
+        Some(session) => session.stream.try_clone().unwrap(),
+        None => return,
+    };
+    let mut reader = BufReader::with_capacity(REC_BUF_SZ, stream_clone);
     let mut header: [u8; 12] = [0; 12];
 
     loop {
+        if !ctx.connections.contains(key) {
+            return;
+        }
+
         if let Err(e) = reader.read_exact(&mut header) {
             if e.kind() != ErrorKind::WouldBlock {
-                ctx.close_and_clean_connection();
+                close_session(ctx, key);
             }
             return;
         }
@@ -94,34 +281,35 @@ pub(crate) fn receive(mut ctx: &mut TracerContext)
         let (cmd, len) = match check_parse_header(&header) {
             Ok((a, b)) => (a, b),
             Err(_) => {
-                ctx.close_and_clean_connection();
-                read_empty(&mut reader, &mut ctx);
+                close_session(ctx, key);
+                read_empty(&mut reader);
                 return;
             },
         };
 
-        execute_command(&mut ctx, cmd, len, &mut reader);
+        execute_command(ctx, key, cmd, len, &mut reader);
     }
 }
 
 
-fn execute_command(mut ctx: &mut TracerContext,
+fn execute_command(ctx: &mut TracerContext,
+                   key: usize,
                    cmd: Command,
                    len: u32,
-                   mut reader: &mut BufReader<TcpStream>)
+                   reader: &mut BufReader<TcpStream>)
 {
     match cmd {
-        Command::TracepointListRequest => send_tracepoint_list(&mut ctx),
+        Command::TracepointListRequest => send_tracepoint_list(ctx, key),
         Command::TracepointEnableRequest =>
-            set_tracepoints(&mut ctx, len, &mut reader, true),
+            set_tracepoints(ctx, key, len, reader, true),
         Command::TracepointDisableRequest =>
-            set_tracepoints(&mut ctx, len, &mut reader, false),
+            set_tracepoints(ctx, key, len, reader, false),
         _ => (), // can never occur, because check_parse_header()
     }
 }
 
 
-fn send_tracepoint_list(mut ctx: &mut TracerContext)
+fn send_tracepoint_list(ctx: &mut TracerContext, key: usize)
 {
     let mut msg: VecDeque<u8> = VecDeque::with_capacity(1024);
 
@@ -139,131 +327,136 @@ fn send_tracepoint_list(mut ctx: &mut TracerContext)
 
     push_front_header(&mut msg, Command::TracepointListReply);
 
-    if send_slices(&mut ctx, &msg).is_err() {
-        ctx.close_and_clean_connection();
+    if send_slices(ctx, key, &msg).is_err() {
+        close_session(ctx, key);
+    }
+}
+
+
+// Fans each flush out to every live connection, filtered by that
+// connection's own enabled-tracepoint set (`ClientSession::tracepoints`)
+// -- a dashboard subscribed to one tracepoint and a file recorder
+// subscribed to another both get served from the one shared `ctx.buffer`
+// drain. A connection whose write fails is dropped; the rest keep going.
+pub(crate) fn send_trace_data(ctx: &mut TracerContext)
+{
+    if ctx.connections.is_empty() {
+        return;
+    }
+
+    let elements: Vec<BufferElement> = ctx.buffer.drain(..).collect();
+    let keys: Vec<usize> = ctx.connections.iter().map(|(key, _)| key).collect();
+
+    for key in keys {
+        send_trace_data_to(ctx, key, &elements);
     }
 }
 
 
-pub(crate) fn send_trace_data(mut ctx: &mut TracerContext)
+fn send_trace_data_to(ctx: &mut TracerContext, key: usize, elements: &[BufferElement])
 {
     let mut que: VecDeque<u8> = VecDeque::with_capacity(QUEUE_TOTAL_SIZE);
     let mut last_was_complete = true;
+    let mut i = 0;
 
-    // Take first element of buffer, if one exists
-    while let Some(front) = ctx.buffer.get(0) {
-        // If there's space in the send-buffer, fill it, otherwise append the
-        // header to the front and send the data
-        if front.len() + que.len() + HEADER_LEN < QUEUE_TOTAL_SIZE {
-            encode_append_trace_data(&mut que, ctx.buffer.pop_front().unwrap());
-            last_was_complete = false;
-        } else {
-            push_front_header(&mut que, Command::TracePush);
+    while i < elements.len() {
+        if !ctx.connections.contains(key) {
+            return;
+        }
 
-            if send_slices(ctx, &que).is_err() {
-                ctx.close_and_clean_connection();
-                return;
-            }
+        let elem = &elements[i];
+        if !ctx.connections[key].tracepoint_enabled(&elem.tracepoint) {
+            i += 1;
+            continue;
+        }
 
-            que.clear();
+        // If there's space in the send-buffer, fill it, otherwise flush
+        // what's been built up so far and keep going
+        if elem.len() + que.len() + HEADER_LEN < QUEUE_TOTAL_SIZE {
+            protocol::encode_append_trace_data(&mut que, &elem.tracepoint,
+                timestamp_to_u64(elem.timestamp), elem.ctx_id, &elem.data);
+            last_was_complete = false;
+            i += 1;
+        } else if flush_batch(ctx, key, &mut que) {
             last_was_complete = true;
+        } else {
+            return;
         }
     }
 
-    if !last_was_complete {
-        push_front_header(&mut que, Command::TracePush);
-
-        if send_slices(&mut ctx, &que).is_err() {
-            ctx.close_and_clean_connection();
-        }
+    if !last_was_complete && !flush_batch(ctx, key, &mut que) {
+        close_session(ctx, key);
     }
 }
 
 
-// FIXME: Take care of signaling the application that the client is not
-// accepting data anymore (WouldBlock)
-//
-// Necessary because you can't send a VecDeque (Ringbuffer) with the default
-// write functions
-//
-// In Case of WouldBlock, most likely the client set the window size to 0.
-fn send_slices(ctx: &mut TracerContext, que: &VecDeque<u8>) ->
-    Result<(), std::io::Error>
+// Wraps `que` in a TracePush header, sends it to one connection, and bumps
+// that connection's own `sequence_no` on success. Always clears `que`
+// regardless of outcome, so callers can keep reusing it for the next batch.
+fn flush_batch(ctx: &mut TracerContext, key: usize, que: &mut VecDeque<u8>) -> bool
 {
-    let (first, second) = que.as_slices();
-    let mut send_buf: Vec<u8> = Vec::with_capacity(que.len());
-
-    // We assume that allocating & copying is less expensive than two syscalls
-    send_buf.extend_from_slice(first);
-    send_buf.extend_from_slice(second);
+    push_front_header(que, Command::TracePush);
+    let sent = send_slices(ctx, key, que).is_ok();
 
-    if let Err(e) = ctx.connection.as_mut().unwrap().write_all(&send_buf) {
-        match e.kind() {
-            ErrorKind::WouldBlock => (),
-            _ => return Err(e),
+    if sent {
+        if let Some(session) = ctx.connections.get_mut(key) {
+            session.sequence_no += 1;
         }
     }
 
-    Ok(())
+    que.clear();
+    sent
 }
 
 
-fn push_front_header(que: &mut VecDeque<u8>, cmd: Command)
+// Queues `que` onto one session's outbound buffer and tries to drain it
+// immediately. A `WouldBlock` no longer silently discards the data: the
+// unsent tail is retained in the session's `pending_write` and drained on
+// the next writable event (see `drain_pending_write`), and crossing
+// `high_water_mark` invokes the application's backpressure callback.
+fn send_slices(ctx: &mut TracerContext, key: usize, que: &VecDeque<u8>) ->
+    Result<(), std::io::Error>
 {
-    // flags are currently unused
-    let flags: u16 = 0;
-    let length = que.len() as u32;
-    for byte in length.to_be_bytes().iter().rev() {
-        que.push_front(*byte);
-    }
+    {
+        let session = match ctx.connections.get_mut(key) {
+            Some(session) => session,
+            None => return Err(std::io::Error::from(ErrorKind::NotConnected)),
+        };
 
-    let tmp = cmd as u16;
-    for byte in tmp.to_be_bytes().iter().rev() {
-        que.push_front(*byte);
-    }
+        let (first, second) = que.as_slices();
 
-    for byte in flags.to_be_bytes().iter().rev() {
-        que.push_front(*byte);
-    }
+        if session.pending_write.is_empty() && (!first.is_empty() || !second.is_empty()) {
+            session.backlog_since = Some(SystemTime::now());
+        }
 
-    for byte in MAGIC_NUMB.iter().rev() {
-        que.push_front(*byte);
+        // We assume that allocating & copying is less expensive than two syscalls
+        session.pending_write.extend(first);
+        session.pending_write.extend(second);
     }
-}
 
+    drain_pending_write(ctx, key);
 
-// Consumes ownership of bufelm
-fn encode_append_trace_data(que: &mut VecDeque<u8>, bufelm: BufferElement)
-{
-    let tp_len = bufelm.tracepoint.len() as u16;
-    let tp_len_arr = tp_len.to_be_bytes();
-    for byte in tp_len_arr.iter() {
-        que.push_back(*byte);
-    }
+    Ok(())
+}
 
-    for letter in bufelm.tracepoint.into_bytes() {
-        que.push_back(letter);
-    }
 
-    let timestamp = timestamp_to_u64(bufelm.timestamp).to_be_bytes();
-    for byte in timestamp.iter() {
-        que.push_back(*byte);
-    }
+pub(crate) fn push_front_header(que: &mut VecDeque<u8>, cmd: Command)
+{
+    protocol::push_front_header(que, cmd)
+}
 
-    let data_len = bufelm.data.len() as u16;
-    let data_len_arr = data_len.to_be_bytes();
-    for byte in data_len_arr.iter() {
-        que.push_back(*byte);
-    }
 
-    // Take by reference with iter, so only one large deallocation at the end
-    for byte in bufelm.data.iter() {
-        que.push_back(*byte);
-    }
+// Consumes ownership of bufelm. Converts the `SystemTime` timestamp to
+// the raw nanosecond count `protocol::encode_append_trace_data` expects,
+// since that function has no `std` to depend on.
+pub(crate) fn encode_append_trace_data(que: &mut VecDeque<u8>, bufelm: BufferElement)
+{
+    protocol::encode_append_trace_data(que, &bufelm.tracepoint,
+        timestamp_to_u64(bufelm.timestamp), bufelm.ctx_id, &bufelm.data)
 }
 
 
-fn set_tracepoints(ctx: &mut TracerContext, len: u32,
+fn set_tracepoints(ctx: &mut TracerContext, key: usize, len: u32,
                        reader: &mut BufReader<TcpStream>,
                        state: bool)
 {
@@ -275,7 +468,7 @@ fn set_tracepoints(ctx: &mut TracerContext, len: u32,
 
     while i < len {
         if reader.read_exact(&mut name_len_arr).is_err() {
-            ctx.close_and_clean_connection();
+            close_session(ctx, key);
             return;
         }
 
@@ -285,12 +478,12 @@ fn set_tracepoints(ctx: &mut TracerContext, len: u32,
         if name_len > MAX_TRACEPOINT_NAME_LEN as u16 {
             eprintln!("tracy: Client violated protocol. Received invalid TP-Name\
                  length: {}", name_len);
-            ctx.close_and_clean_connection();
+            close_session(ctx, key);
             return;
         }
 
         if reader.read_exact(&mut tp_name_arr[..name_len as usize]).is_err() {
-            ctx.close_and_clean_connection();
+            close_session(ctx, key);
             return;
         }
         i += name_len as u32;
@@ -299,8 +492,20 @@ fn set_tracepoints(ctx: &mut TracerContext, len: u32,
         tp_name = std::str::from_utf8(&tp_name_arr[..name_len as usize])
             .unwrap_or_default();
 
-        if let Some(val_ref) = ctx.tracepoints.get_mut(tp_name) {
-            val_ref.store(state, Ordering::SeqCst);
+        // Only this client's own view changes; the aggregate flag
+        // `tracy_submit` checks is recomputed from every connected
+        // client's view, so data keeps flowing as long as anyone still
+        // wants it.
+        if ctx.tracepoints.contains_key(tp_name) {
+            if let Some(session) = ctx.connections.get_mut(key) {
+                session.tracepoints.insert(tp_name.to_string(), state);
+            }
+
+            if let Some(flag) = ctx.tracepoints.get(tp_name) {
+                let any_enabled = ctx.connections.iter()
+                    .any(|(_, session)| session.tracepoint_enabled(tp_name));
+                flag.store(any_enabled, Ordering::SeqCst);
+            }
         }
 
         tp_name_arr = [0u8; MAX_TRACEPOINT_NAME_LEN];
@@ -308,24 +513,18 @@ fn set_tracepoints(ctx: &mut TracerContext, len: u32,
 }
 
 
-// reads the socket empty and throws the data away
-// Closes connection if there's a problem other than WouldBlock
-fn read_empty(reader: &mut BufReader<TcpStream>, ctx: &mut TracerContext)
+// Reads the socket empty and throws the data away. Used right after a
+// malformed header already closed the session, so there is nothing left
+// to close here -- just drain whatever the client still sent so a stale
+// readable-event doesn't keep re-firing.
+fn read_empty(reader: &mut BufReader<TcpStream>)
 {
-    // TODO: Which size on the stack is acceptable?
     let mut trash: [u8; 64] = [0u8; 64];
 
     loop {
         match reader.read(&mut trash) {
-            Ok(n) => if n == 0 { return },
-            Err(e) => match e.kind() {
-                ErrorKind::WouldBlock => return,
-                _ => {
-                    eprintln!("tracy: Read error: {}", e);
-                    ctx.close_and_clean_connection();
-                    return;
-                },
-            },
+            Ok(n) if n > 0 => continue,
+            _ => return,
         }
     }
 }
@@ -333,102 +532,7 @@ fn read_empty(reader: &mut BufReader<TcpStream>, ctx: &mut TracerContext)
 
 fn check_parse_header(header: &[u8; 12]) -> Result<(Command, u32), ()>
 {
-    let mut magic_no: [u8; 4] = [0; 4];
-    let mut flags: [u8; 2] = [0; 2];
-    let mut command: [u8; 2] = [0; 2];
-    let mut length: [u8; 4] = [0; 4];
-
-    for i in 0..4 {
-        magic_no[i] = header[i];
-    }
-
-    if !check_magic_number(magic_no) {
-        return Err(());
-    }
-
-    for i in 4..6 {
-        flags[i-4] = header[i];
-    }
-
-    for i in 6..8 {
-        command[i-6] = header[i];
-    }
-
-    for i in 8..12 {
-        length[i-8] = header[i];
-    }
-
-    let len = u32::from_be_bytes(length);
-    let flags = u16::from_be_bytes(flags);
-    let cmd = u16::from_be_bytes(command);
-
-    // Check if client performs one of the permitted commands and check if the
-    // data-length for these cases makes sense
-    let cmd = cmd_number_to_enum(cmd);
-    if check_cmd_validity(&cmd, len).is_err() {
-        eprintln!("Tracy: Received invalid command.");
-    }
-    check_flags(flags)?;
-
-    Ok((cmd, len))
-}
-
-
-// Flags are currently unused. If they're not all 0, reject request
-fn check_flags(flags: u16) -> Result<(), ()>
-{
-    if flags != 0 {
-        eprintln!("Tracy: Received header flags invalid.");
-        Err(())
-    } else {
-        Ok(())
-    }
-}
-
-
-fn cmd_number_to_enum(cmd: u16) -> Command
-{
-    match cmd {
-        cmd if cmd == Command::TracepointListRequest as u16 =>
-            Command::TracepointListRequest,
-        cmd if cmd == Command::TracepointEnableRequest as u16 =>
-            Command::TracepointEnableRequest,
-        cmd if cmd == Command::TracepointDisableRequest as u16 =>
-            Command::TracepointDisableRequest,
-        cmd if cmd == Command::TracepointListReply as u16 =>
-            Command::TracepointListReply,
-        cmd if cmd == Command::TracePush as u16 => 
-            Command::TracePush,
-        _ => 
-            Command::Invalid,
-    }
-}
-
-
-fn check_cmd_validity(cmd: &Command, len: u32) -> Result<(), ()>
-{
-    match cmd {
-        Command::TracepointListRequest => 
-            if len != 0 {
-                Err(())
-            } else {
-                Ok(())
-            },
-        Command::TracepointEnableRequest => 
-            if len == 0 {
-                Err(())
-            } else {
-                Ok(())
-            },
-        Command::TracepointDisableRequest =>
-            if len == 0 {
-                Err(())
-            } else {
-                Ok(())
-            },
-        // Client is only allowed to give the upper commands
-        _ => Err(())
-    }
+    protocol::check_parse_header(header)
 }
 
 
@@ -445,10 +549,3 @@ fn timestamp_to_u64(time: SystemTime) -> u64
         Err(_) => 0,
     }
 }
-
-
-fn check_magic_number(number: [u8; 4]) -> bool
-{
-    number[0]==MAGIC_NUMB[0] && number[1]==MAGIC_NUMB[1] 
-        && number[2]==MAGIC_NUMB[2] && number[3]==MAGIC_NUMB[3]
-}
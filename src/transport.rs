@@ -0,0 +1,72 @@
+// Copyright 2019, 2020 Rohde & Schwarz GmbH & Co KG
+//      philipp.stanner@rohde-schwarz.com
+//      hagen.pfeifer@rohde-schwarz.com
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Seams an embedded build plugs its own UART/RTT/custom-socket code into,
+// so the `protocol` framing and tracepoint-enable/disable logic can be
+// reused unchanged outside of `std`. `smol_runtime`'s `SmolSocket` (below)
+// is the only current implementer; `std_runtime`/`tcp_handler` still
+// drive `mio`/`TcpStream` directly rather than going through `Transport`.
+
+pub trait Transport {
+    type Error;
+
+    fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+    fn try_write(&mut self, buf: &[u8]) -> Result<usize, Self::Error>;
+}
+
+// Nanoseconds since an implementation-defined epoch -- a bare-metal impl
+// might just return a free-running monotonic counter, since the wire
+// format doesn't interpret this value.
+pub trait Clock {
+    fn now_nanos(&self) -> u64;
+}
+
+// Diagnostics sink an embedded caller points at its own UART/RTT instead
+// of `println!`/`eprintln!`, which `no_std` doesn't have.
+pub trait Log {
+    fn log(&self, msg: &core::fmt::Arguments);
+}
+
+// smoltcp has no OS socket layer underneath it: a `smoltcp::socket::TcpSocket`
+// only becomes readable/writable as a side effect of `EthernetInterface::poll`
+// pumping packets through it, so this impl can't live on the socket type
+// itself -- `smol_runtime` hands out a short-lived reference into its
+// `SocketSet` each time it needs one.
+#[cfg(feature = "smoltcp")]
+mod smoltcp_impl {
+    use super::Transport;
+    use smoltcp::socket::TcpSocket;
+    use smoltcp::Error;
+
+    pub struct SmolSocket<'a, 'b: 'a>(pub &'a mut TcpSocket<'b>);
+
+    impl<'a, 'b> Transport for SmolSocket<'a, 'b> {
+        type Error = Error;
+
+        fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>
+        {
+            if !self.0.can_recv() {
+                return Ok(0);
+            }
+
+            self.0.recv_slice(buf)
+        }
+
+        fn try_write(&mut self, buf: &[u8]) -> Result<usize, Self::Error>
+        {
+            if !self.0.can_send() {
+                return Ok(0);
+            }
+
+            self.0.send_slice(buf)
+        }
+    }
+}
+
+#[cfg(feature = "smoltcp")]
+pub use smoltcp_impl::SmolSocket;
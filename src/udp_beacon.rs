@@ -9,8 +9,8 @@
 use std::net::UdpSocket;
 use std::io::Error;
 
-use crate::{TracerContext, SERVER_VERSION, PROTOCOLL_VERSION};
-use crate::tcp_handler::MAGIC_NUMB;
+use crate::std_runtime::{TracerContext, SERVER_VERSION, PROTOCOLL_VERSION};
+use crate::protocol::MAGIC_NUMB;
 
 
 // Bind to a interface for udp announcements, if the user specified one
@@ -49,15 +49,57 @@ fn format_json(ctx: &TracerContext) -> String
 {
     let mut announce_interval: u64 = ctx.app_cfg.announce_interval.as_secs();
     announce_interval += ctx.app_cfg.announce_interval.subsec_millis() as u64;
+    #[cfg(feature = "quic")]
+    let transport = if ctx.quic.is_some() { "quic" } else { "tcp" };
+    #[cfg(not(feature = "quic"))]
+    let transport = "tcp";
+
+    // The QUIC socket is bound to its own ephemeral UDP port, separate from
+    // the TCP listener's `port` below, so a collector that wants to attach
+    // over QUIC needs this value too. `null` when QUIC isn't active.
+    #[cfg(feature = "quic")]
+    let quic_port: Option<u16> = ctx.quic.as_ref()
+        .and_then(|q| q.sock.local_addr().ok())
+        .map(|a| a.port());
+    #[cfg(not(feature = "quic"))]
+    let quic_port: Option<u16> = None;
+    let quic_port_json = quic_port
+        .map(|p| p.to_string())
+        .unwrap_or_else(|| "null".to_string());
+
+    #[cfg(feature = "shm")]
+    let shm_available = ctx.shm.is_some();
+    #[cfg(not(feature = "shm"))]
+    let shm_available = false;
+
+    let port = ctx.app_cfg.advertise_port
+        .unwrap_or_else(|| ctx.listener.local_addr().unwrap().port());
+
+    // Defaults to the configured hostname; overridden by
+    // `advertise_addresses` for NAT'd/port-forwarded/multi-homed setups.
+    // A dual-homed host can list both its internal and external address
+    // so the client can pick whichever one actually routes.
+    let addresses: Vec<String> = if ctx.app_cfg.advertise_addresses.is_empty() {
+        vec![ctx.app_cfg.hostname.clone()]
+    } else {
+        ctx.app_cfg.advertise_addresses.clone()
+    };
+    let addresses_json = addresses.iter()
+        .map(|a| format!("\"{}\"", a))
+        .collect::<Vec<String>>()
+        .join(", ");
+
     let s = format!("{{ \"sequence_nr\": {},\
                 \"server_version\": \"{}\", \"protocoll_version\": \"{}\",\
                 \"update_interval_msecs\": {},\
                 \"hostname\": \"{}\", \"process_name\": \"{}\",\
-                \"port\": {}}}",
+                \"port\": {}, \"transport\": \"{}\", \"shm\": {},\
+                \"quic_port\": {},\
+                \"advertise_addresses\": [{}]}}",
                 ctx.sequence_no, SERVER_VERSION, PROTOCOLL_VERSION,
                 announce_interval, ctx.app_cfg.hostname,
                 ctx.app_cfg.process_name,
-                ctx.listener.local_addr().unwrap().port());
+                port, transport, shm_available, quic_port_json, addresses_json);
 
     String::from(s)
 }